@@ -0,0 +1,168 @@
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use log::info;
+use tokio::process::Command;
+use crate::SecureLinkError;
+
+/// Supplies the token sent with every global channel join, it is asked again on each reconnect.
+/// `refresh` is set after the server denied the previous token, providers that cache should
+/// fetch a new one then. `AuthTokenError` is retried under the reconnect policy, e.g. while a
+/// secrets backend is briefly down. `AuthTokenConfigError` stops reconnecting for problems a
+/// retry cannot fix.
+pub trait AuthTokenProvider: Send + Sync + 'static {
+    fn auth_token(&self, refresh: bool) -> impl Future<Output = Result<String, SecureLinkError>> + Send;
+}
+
+type BoxedAuthTokenFuture<'a> = Pin<Box<dyn Future<Output = Result<String, SecureLinkError>> + Send + 'a>>;
+
+/// Object safe form of `AuthTokenProvider`, like `DynConnector`.
+pub(crate) trait DynAuthTokenProvider: Send + Sync {
+    fn auth_token_boxed(&self, refresh: bool) -> BoxedAuthTokenFuture<'_>;
+}
+
+impl<P: AuthTokenProvider> DynAuthTokenProvider for P {
+    fn auth_token_boxed(&self, refresh: bool) -> BoxedAuthTokenFuture<'_> {
+        Box::pin(self.auth_token(refresh))
+    }
+}
+
+pub struct StaticAuthToken(String);
+
+impl StaticAuthToken {
+
+    pub fn new(auth_token: &str) -> Self {
+        StaticAuthToken(auth_token.to_string())
+    }
+}
+
+impl AuthTokenProvider for StaticAuthToken {
+
+    async fn auth_token(&self, _refresh: bool) -> Result<String, SecureLinkError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Reads the variable of this process on every join. Only the process itself can change its
+/// environment, so for tokens rotated from outside prefer `FileAuthToken` or `CommandAuthToken`.
+pub struct EnvAuthToken {
+    variable: String
+}
+
+impl EnvAuthToken {
+
+    pub fn new(variable: &str) -> Self {
+        EnvAuthToken { variable: variable.to_string() }
+    }
+}
+
+impl AuthTokenProvider for EnvAuthToken {
+
+    async fn auth_token(&self, _refresh: bool) -> Result<String, SecureLinkError> {
+        std::env::var(&self.variable)
+            .map(|auth_token| auth_token.trim().to_string())
+            // Nothing outside this process can set it later
+            .map_err(|err| SecureLinkError::AuthTokenConfigError(format!("{}: {}", self.variable, err)))
+    }
+}
+
+/// Keeps the file content and reads it again once the file was modified.
+pub struct FileAuthToken {
+    path: PathBuf,
+    cached: Mutex<Option<(SystemTime, String)>>
+}
+
+impl FileAuthToken {
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileAuthToken {
+            path: path.into(),
+            cached: Mutex::new(None)
+        }
+    }
+}
+
+impl AuthTokenProvider for FileAuthToken {
+
+    async fn auth_token(&self, refresh: bool) -> Result<String, SecureLinkError> {
+
+        let modified = tokio::fs::metadata(&self.path).await
+            .and_then(|metadata| metadata.modified())
+            .map_err(|err| SecureLinkError::AuthTokenError(format!("{}: {}", self.path.display(), err)))?;
+
+        if !refresh {
+            if let Some((cached_modified, auth_token)) = self.cached.lock().unwrap().as_ref() {
+                if *cached_modified == modified {
+                    return Ok(auth_token.clone());
+                }
+            }
+        }
+
+        let auth_token = tokio::fs::read_to_string(&self.path).await
+            .map_err(|err| SecureLinkError::AuthTokenError(format!("{}: {}", self.path.display(), err)))?
+            .trim()
+            .to_string();
+
+        info!("read auth token from {}", self.path.display());
+
+        *self.cached.lock().unwrap() = Some((modified, auth_token.clone()));
+
+        Ok(auth_token)
+    }
+}
+
+/// Runs the command on every join and uses its trimmed stdout, e.g. a secrets manager CLI.
+pub struct CommandAuthToken {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration
+}
+
+impl CommandAuthToken {
+
+    const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+    pub fn new(program: &str, args: &[&str]) -> Self {
+        CommandAuthToken {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout: Duration::from_secs(Self::DEFAULT_TIMEOUT_SECS)
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl AuthTokenProvider for CommandAuthToken {
+
+    async fn auth_token(&self, _refresh: bool) -> Result<String, SecureLinkError> {
+
+        let output = Command::new(&self.program).args(&self.args).kill_on_drop(true).output();
+
+        let output = tokio::time::timeout(self.timeout, output).await
+            .map_err(|_| SecureLinkError::AuthTokenError(format!("{} did not finish within {:?}", self.program, self.timeout)))?
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound | ErrorKind::PermissionDenied => SecureLinkError::AuthTokenConfigError(format!("{}: {}", self.program, err)),
+                _ => SecureLinkError::AuthTokenError(format!("{}: {}", self.program, err))
+            })?;
+
+        if !output.status.success() {
+            return Err(SecureLinkError::AuthTokenError(format!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        String::from_utf8(output.stdout)
+            .map(|auth_token| auth_token.trim().to_string())
+            .map_err(|err| SecureLinkError::AuthTokenError(format!("{}: {}", self.program, err)))
+    }
+}
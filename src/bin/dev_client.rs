@@ -1,6 +1,10 @@
 use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::runtime::Runtime;
-use secure_link_client::SecureLink;
+use secure_link_client::{ClientCertificate, EndpointSelection, FileAuthToken, ReconnectPolicy, SecureLinkBuilder, SecureLinkEndpoint, TrustAnchorSource};
+
+const SHUTDOWN_DRAIN_TIMEOUT_SECS: u64 = 30;
 
 fn main() {
 
@@ -8,27 +12,96 @@ fn main() {
     
     dotenv::dotenv().ok();
 
-    let auth_token = env::var("AUTH_TOKEN")
-        .expect("AUTH_TOKEN environment variable is required");
+    // A token file is read again whenever it changes, so it can be rotated without a restart
+    let auth_token_file = env::var("AUTH_TOKEN_FILE").ok();
+
+    // Comma separated `host:port[@weight]` list, takes precedence over the single host and port
+    let secure_link_server_endpoints: Vec<SecureLinkEndpoint> = match env::var("SECURE_LINK_SERVER_ENDPOINTS") {
+        Ok(endpoints) => endpoints
+            .split(',')
+            .filter(|endpoint| !endpoint.trim().is_empty())
+            .map(|endpoint| endpoint.parse().expect("SECURE_LINK_SERVER_ENDPOINTS must be a list of host:port[@weight]"))
+            .collect(),
+        Err(_) => {
+
+            let secure_link_server_host = env::var("SECURE_LINK_SERVER_HOST")
+                .expect("SECURE_LINK_SERVER_HOST environment variable is required");
+
+            let secure_link_server_port: u16 = env::var("SECURE_LINK_SERVER_PORT")
+                .expect("SECURE_LINK_SERVER_PORT environment variable is required")
+                .parse()
+                .expect("SECURE_LINK_SERVER_PORT must be a valid port number");
 
-    let secure_link_server_host = env::var("SECURE_LINK_SERVER_HOST")
-        .expect("SECURE_LINK_SERVER_HOST environment variable is required");
+            vec![SecureLinkEndpoint::new(&secure_link_server_host, secure_link_server_port)]
+        }
+    };
+
+    let endpoint_selection = match env::var("SECURE_LINK_SERVER_ENDPOINT_SELECTION").as_deref() {
+        Ok("weighted") => EndpointSelection::Weighted,
+        _ => EndpointSelection::Ordered
+    };
+
+    let client_certificate = match (env::var("SECURE_LINK_CLIENT_CERT_PATH"), env::var("SECURE_LINK_CLIENT_KEY_PATH")) {
+        (Ok(cert_chain_path), Ok(private_key_path)) => Some(ClientCertificate::from_pem_files(cert_chain_path, private_key_path)),
+        _ => None
+    };
+
+    // A PEM/DER file, a directory of them or `system` for the OS bundle
+    let trust_anchors = env::var("SECURE_LINK_TRUST_ANCHORS").ok().map(|trust_anchors| match trust_anchors.as_str() {
+        "system" => TrustAnchorSource::SystemStore,
+        path if PathBuf::from(path).is_dir() => TrustAnchorSource::Directory(PathBuf::from(path)),
+        path => TrustAnchorSource::File(PathBuf::from(path))
+    });
 
-    let secure_link_server_port: u16 = env::var("SECURE_LINK_SERVER_PORT")
-        .expect("SECURE_LINK_SERVER_PORT environment variable is required")
-        .parse()
-        .expect("SECURE_LINK_SERVER_PORT must be a valid port number");
+    let use_webpki_roots = env::var("SECURE_LINK_USE_WEBPKI_ROOTS").map_or(true, |value| value != "false");
 
     Runtime::new().unwrap().block_on(async {
 
-        let secure_link_connection_result =
-            SecureLink::connect_to_global_channel(
-                &secure_link_server_host,
-                secure_link_server_port,
-                &auth_token
-            ).await.unwrap();
+        let secure_link_builder = match auth_token_file {
+            Some(auth_token_file) => {
+                SecureLinkBuilder::with_auth_token_provider(secure_link_server_endpoints, FileAuthToken::new(auth_token_file))
+            }
+            None => {
+                let auth_token = env::var("AUTH_TOKEN")
+                    .expect("AUTH_TOKEN or AUTH_TOKEN_FILE environment variable is required");
+                SecureLinkBuilder::with_endpoints(secure_link_server_endpoints, &auth_token)
+            }
+        };
+
+        let mut secure_link_builder = secure_link_builder
+            .endpoint_selection(endpoint_selection)
+            .use_webpki_roots(use_webpki_roots);
+
+        if let Some(trust_anchors) = trust_anchors {
+            secure_link_builder = secure_link_builder.add_trust_anchors(trust_anchors);
+        }
+
+        if let Some(client_certificate) = client_certificate {
+            secure_link_builder = secure_link_builder.client_certificate(client_certificate);
+        }
+
+        let reconnect_policy = ReconnectPolicy::default();
+
+        // Waits for the server instead of exiting when it is not up yet
+        let secure_link_connection_result = match secure_link_builder.connect_with_reconnect(&reconnect_policy).await {
+            Ok(secure_link) => secure_link,
+            Err(err) => {
+                eprintln!("{:?}", err);
+                std::process::exit(1);
+            }
+        };
         
-        let res = secure_link_connection_result.run_message_loop().await;
+        let shutdown_handle = secure_link_connection_result.shutdown_handle();
+
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                shutdown_handle.shutdown(Duration::from_secs(SHUTDOWN_DRAIN_TIMEOUT_SECS));
+            }
+        });
+
+        let res = secure_link_connection_result
+            .run_message_loop_with_reconnect(reconnect_policy)
+            .await;
         
         eprintln!("{:?}", res);
         
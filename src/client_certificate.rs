@@ -0,0 +1,152 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use log::{info, warn};
+use rustls::client::ResolvesClientCert;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::sign::CertifiedKey;
+use rustls::{InconsistentKeys, SignatureScheme};
+use crate::SecureLinkError;
+
+/// A PEM certificate chain and private key (PKCS#8, PKCS#1 or SEC1) presented to the secure
+/// link server. The files are checked on every handshake and reloaded once either changed,
+/// so a rotated certificate is picked up by the next global or proxy channel connection.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    cert_chain_path: PathBuf,
+    private_key_path: PathBuf
+}
+
+impl ClientCertificate {
+
+    pub fn from_pem_files(cert_chain_path: impl Into<PathBuf>, private_key_path: impl Into<PathBuf>) -> Self {
+        ClientCertificate {
+            cert_chain_path: cert_chain_path.into(),
+            private_key_path: private_key_path.into()
+        }
+    }
+}
+
+pub(crate) struct ClientCertificateResolver {
+    client_certificate: ClientCertificate,
+    loaded: Mutex<LoadedClientCertificate>
+}
+
+struct LoadedClientCertificate {
+    certified_key: Arc<CertifiedKey>,
+    modified: (Option<SystemTime>, Option<SystemTime>)
+}
+
+impl ClientCertificateResolver {
+
+    /// Fails if the files cannot be loaded right away, later reload failures keep the
+    /// previous certificate.
+    pub fn new(client_certificate: ClientCertificate) -> Result<Self, SecureLinkError> {
+
+        let modified = modification_times(&client_certificate);
+        let certified_key = load_certified_key(&client_certificate)?;
+
+        Ok(ClientCertificateResolver {
+            client_certificate,
+            loaded: Mutex::new(LoadedClientCertificate { certified_key, modified })
+        })
+    }
+
+    fn current_certified_key(&self) -> Arc<CertifiedKey> {
+
+        let mut loaded = self.loaded.lock().unwrap();
+
+        let modified = modification_times(&self.client_certificate);
+
+        if modified != loaded.modified {
+            match load_certified_key(&self.client_certificate) {
+                Ok(certified_key) => {
+                    info!("reloaded client certificate from {}", self.client_certificate.cert_chain_path.display());
+                    loaded.certified_key = certified_key;
+                    loaded.modified = modified;
+                }
+                Err(err) => {
+                    // A rotation may be half written, the next handshake tries again
+                    warn!("failed to reload client certificate, keeping the previous one: {}", err);
+                }
+            }
+        }
+
+        loaded.certified_key.clone()
+    }
+}
+
+impl fmt::Debug for ClientCertificateResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCertificateResolver")
+            .field("client_certificate", &self.client_certificate)
+            .finish()
+    }
+}
+
+impl ResolvesClientCert for ClientCertificateResolver {
+
+    fn resolve(&self, _root_hint_subjects: &[&[u8]], sigschemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+
+        let certified_key = self.current_certified_key();
+
+        match certified_key.key.choose_scheme(sigschemes) {
+            Some(_) => Some(certified_key),
+            None => {
+                warn!("client certificate key does not support any signature scheme offered by the server");
+                None
+            }
+        }
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+fn modification_times(client_certificate: &ClientCertificate) -> (Option<SystemTime>, Option<SystemTime>) {
+    (modification_time(&client_certificate.cert_chain_path), modification_time(&client_certificate.private_key_path))
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn load_certified_key(client_certificate: &ClientCertificate) -> Result<Arc<CertifiedKey>, SecureLinkError> {
+
+    let cert_chain_path = &client_certificate.cert_chain_path;
+    let private_key_path = &client_certificate.private_key_path;
+
+    let cert_chain = CertificateDer::pem_file_iter(cert_chain_path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| SecureLinkError::ClientCertificateError(format!("{}: {:?}", cert_chain_path.display(), err)))?;
+
+    if cert_chain.is_empty() {
+        return Err(SecureLinkError::ClientCertificateError(format!("{}: no certificate found", cert_chain_path.display())));
+    }
+
+    let private_key = PrivateKeyDer::from_pem_file(private_key_path)
+        .map_err(|err| SecureLinkError::ClientCertificateError(format!("{}: {:?}", private_key_path.display(), err)))?;
+
+    let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&private_key)
+        .map_err(|err| SecureLinkError::ClientCertificateError(format!("{}: {}", private_key_path.display(), err)))?;
+
+    let certified_key = CertifiedKey::new(cert_chain, signing_key);
+
+    // A rotation writes the two files one after the other, a mismatch means it is not done yet
+    match certified_key.keys_match() {
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => {}
+        Err(err) => {
+            return Err(SecureLinkError::ClientCertificateError(format!(
+                "{} does not match {}: {}",
+                private_key_path.display(),
+                cert_chain_path.display(),
+                err
+            )));
+        }
+    }
+
+    Ok(Arc::new(certified_key))
+}
@@ -0,0 +1,83 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpStream};
+use crate::protocol::global_channel_message::ProxyDestination;
+
+/// Opens the stream a proxy channel forwards to. Failing with `ErrorKind::PermissionDenied`
+/// is reported to the server as a bad destination, any other error as an unreachable one.
+pub trait Connector: Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// `addresses` are the resolved addresses of `destination` that the destination policy
+    /// allowed. They are empty when the policy allowed the destination by name, the connector
+    /// resolves it then. Connecting anywhere else bypasses the policy.
+    fn connect(&self, destination: &ProxyDestination, addresses: &[SocketAddr]) -> impl Future<Output = Result<Self::Stream, Error>> + Send;
+}
+
+pub trait DestinationStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> DestinationStream for T {}
+
+pub(crate) type BoxedDestinationStream = Box<dyn DestinationStream>;
+
+type BoxedConnectFuture<'a> = Pin<Box<dyn Future<Output = Result<BoxedDestinationStream, Error>> + Send + 'a>>;
+
+/// Object safe form of `Connector` so the configured connector can be stored without generics.
+pub(crate) trait DynConnector: Send + Sync {
+    fn connect_boxed<'a>(&'a self, destination: &'a ProxyDestination, addresses: &'a [SocketAddr]) -> BoxedConnectFuture<'a>;
+}
+
+impl<C: Connector> DynConnector for C {
+    fn connect_boxed<'a>(&'a self, destination: &'a ProxyDestination, addresses: &'a [SocketAddr]) -> BoxedConnectFuture<'a> {
+        Box::pin(async move {
+            let stream = self.connect(destination, addresses).await?;
+            Ok(Box::new(stream) as BoxedDestinationStream)
+        })
+    }
+}
+
+/// Connects over TCP to the first allowed address that accepts the connection.
+#[derive(Debug, Default)]
+pub struct TcpConnector;
+
+impl TcpConnector {
+
+    pub fn new() -> Self {
+        TcpConnector
+    }
+}
+
+impl Connector for TcpConnector {
+    type Stream = TcpStream;
+
+    async fn connect(&self, destination: &ProxyDestination, addresses: &[SocketAddr]) -> Result<TcpStream, Error> {
+
+        let resolved_addresses: Vec<SocketAddr>;
+
+        let addresses = match addresses.is_empty() {
+            true => {
+                resolved_addresses = lookup_host((destination.ip.as_str(), destination.port)).await?.collect();
+                &resolved_addresses
+            }
+            false => addresses
+        };
+
+        let mut last_error = None;
+
+        for address in addresses {
+            match TcpStream::connect(address).await {
+                Ok(tcp_stream) => return Ok(tcp_stream),
+                Err(err) => {
+                    debug!("failed to connect to destination address {}: {}", address, err);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| Error::new(ErrorKind::NotFound, "no destination address to connect to")))
+    }
+}
@@ -1,8 +1,10 @@
-use std::sync::{Arc};
+use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncWriteExt, WriteHalf};
 use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
+use crate::protocol::frame_codec::FrameCodec;
 use crate::protocol::global_channel_message::CsGlobalChannelMessage;
+use crate::protocol::protocol_version::NegotiatedProtocol;
 use crate::SecureLinkError;
 
 #[derive(Clone)]
@@ -10,11 +12,12 @@ pub struct CsGlobalChannelSender(Arc<CsGlobalChannelSenderInner>);
 
 impl CsGlobalChannelSender {
 
-    pub fn new(sender: WriteHalf<TlsStream<TcpStream>>) -> CsGlobalChannelSender {
+    pub fn new(sender: WriteHalf<TlsStream<TcpStream>>, frame_codec: FrameCodec) -> CsGlobalChannelSender {
         CsGlobalChannelSender(
             Arc::new(
                 CsGlobalChannelSenderInner {
                     sender: tokio::sync::Mutex::new(sender),
+                    frame_codec
                 }
             )
         )
@@ -23,10 +26,35 @@ impl CsGlobalChannelSender {
     pub async fn send_cs_global_channel_message(&self, global_channel_message: CsGlobalChannelMessage) -> Result<(), SecureLinkError> {
         self.0.send_cs_global_channel_message(global_channel_message).await
     }
+
+    pub async fn shutdown(&self) {
+        let mut sender = self.0.sender.lock().await;
+        let _ = sender.shutdown().await; // Ignore shutdown errors
+    }
+}
+
+/// The sender of the global channel that is connected right now, replaced on every reconnect.
+#[derive(Default)]
+pub(crate) struct CurrentGlobalChannelSender(Mutex<Option<(CsGlobalChannelSender, NegotiatedProtocol)>>);
+
+impl CurrentGlobalChannelSender {
+
+    pub fn set(&self, global_channel_sender: CsGlobalChannelSender, protocol: NegotiatedProtocol) {
+        *self.0.lock().unwrap() = Some((global_channel_sender, protocol));
+    }
+
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    pub fn get(&self) -> Option<(CsGlobalChannelSender, NegotiatedProtocol)> {
+        self.0.lock().unwrap().clone()
+    }
 }
 
 struct CsGlobalChannelSenderInner {
-    sender: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>
+    sender: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    frame_codec: FrameCodec
 }
 
 impl CsGlobalChannelSenderInner {
@@ -36,16 +64,7 @@ impl CsGlobalChannelSenderInner {
         message: CsGlobalChannelMessage
     ) -> Result<(), SecureLinkError> {
 
-        let message_json =
-            serde_json::to_string(&message)
-                .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })?;
-
-        let pdu_length = (message_json.len() as u32).to_be_bytes();
-
-        let mut global_channel_cs_pdu = vec![0u8];
-
-        global_channel_cs_pdu.extend_from_slice(&pdu_length);
-        global_channel_cs_pdu.extend_from_slice(message_json.as_bytes());
+        let global_channel_cs_pdu = self.frame_codec.encode(&message)?;
 
         // Scope the lock so it's dropped before the await
         {
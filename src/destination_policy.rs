@@ -0,0 +1,428 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use log::warn;
+use tokio::net::lookup_host;
+use crate::protocol::global_channel_message::ProxyDestination;
+use crate::SecureLinkError;
+
+/// Evaluated for every address a proxy destination resolves to, before connecting to it.
+pub trait DestinationPolicy: Send + Sync {
+    fn is_allowed(&self, destination: &ProxyDestination, address: SocketAddr) -> bool;
+
+    /// Decides from the destination alone when the resolved addresses cannot change the
+    /// outcome, which skips resolving it. `None` asks for `is_allowed` on every address.
+    fn is_allowed_by_name(&self, _destination: &ProxyDestination) -> Option<bool> {
+        None
+    }
+}
+
+/// Keeps the addresses of the destination that the policy allows. Runs before every connector,
+/// so a custom connector cannot widen what the policy permits. Empty when the policy allowed
+/// the destination by name, the connector resolves it itself then.
+pub(crate) async fn allowed_addresses(destination_policy: &dyn DestinationPolicy, destination: &ProxyDestination) -> Result<Vec<SocketAddr>, Error> {
+
+    match destination_policy.is_allowed_by_name(destination) {
+        Some(true) => return Ok(Vec::new()),
+        Some(false) => {
+            warn!("destination {}:{} rejected by destination policy", destination.ip, destination.port);
+            return Err(Error::new(ErrorKind::PermissionDenied, "destination rejected by destination policy"));
+        }
+        None => {}
+    }
+
+    let addresses: Vec<SocketAddr> = lookup_host((destination.ip.as_str(), destination.port)).await?.collect();
+
+    if addresses.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "destination did not resolve to any address"));
+    }
+
+    let allowed: Vec<SocketAddr> = addresses.into_iter()
+        .filter(|address| {
+            let allowed = destination_policy.is_allowed(destination, *address);
+            if !allowed {
+                warn!("destination {}:{} address {} rejected by destination policy", destination.ip, destination.port, address);
+            }
+            allowed
+        })
+        .collect();
+
+    if allowed.is_empty() {
+        return Err(Error::new(ErrorKind::PermissionDenied, "destination rejected by destination policy"));
+    }
+
+    Ok(allowed)
+}
+
+pub struct AllowAllDestinations;
+
+impl DestinationPolicy for AllowAllDestinations {
+    fn is_allowed(&self, _destination: &ProxyDestination, _address: SocketAddr) -> bool {
+        true
+    }
+
+    fn is_allowed_by_name(&self, _destination: &ProxyDestination) -> Option<bool> {
+        Some(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyAction {
+    Allow,
+    Deny
+}
+
+/// Ordered allow/deny rules, the first matching rule wins.
+#[derive(Debug, Clone)]
+pub struct DestinationRules {
+    rules: Vec<(PolicyAction, DestinationMatcher)>,
+    default_action: PolicyAction
+}
+
+impl DestinationRules {
+
+    pub fn new(default_action: PolicyAction) -> Self {
+        DestinationRules {
+            rules: Vec::new(),
+            default_action
+        }
+    }
+
+    pub fn allow_all() -> Self {
+        Self::new(PolicyAction::Allow)
+    }
+
+    pub fn deny_all() -> Self {
+        Self::new(PolicyAction::Deny)
+    }
+
+    pub fn allow(mut self, matcher: DestinationMatcher) -> Self {
+        self.rules.push((PolicyAction::Allow, matcher));
+        self
+    }
+
+    pub fn deny(mut self, matcher: DestinationMatcher) -> Self {
+        self.rules.push((PolicyAction::Deny, matcher));
+        self
+    }
+
+    pub fn evaluate(&self, destination: &ProxyDestination, address: SocketAddr) -> PolicyAction {
+        self.rules.iter()
+            .find(|(_, matcher)| matcher.matches(destination, address))
+            .map(|(action, _)| *action)
+            .unwrap_or(self.default_action)
+    }
+
+    /// `None` once a CIDR rule has to be checked against the addresses of a hostname.
+    pub fn evaluate_by_name(&self, destination: &ProxyDestination) -> Option<PolicyAction> {
+
+        for (action, matcher) in &self.rules {
+            if matcher.matches_by_name(destination)? {
+                return Some(*action);
+            }
+        }
+
+        Some(self.default_action)
+    }
+}
+
+impl DestinationPolicy for DestinationRules {
+    fn is_allowed(&self, destination: &ProxyDestination, address: SocketAddr) -> bool {
+        self.evaluate(destination, address) == PolicyAction::Allow
+    }
+
+    fn is_allowed_by_name(&self, destination: &ProxyDestination) -> Option<bool> {
+        self.evaluate_by_name(destination).map(|action| action == PolicyAction::Allow)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DestinationMatcher {
+    host: HostMatcher,
+    ports: RangeInclusive<u16>
+}
+
+#[derive(Debug, Clone)]
+enum HostMatcher {
+    Any,
+    Cidr(IpCidr),
+    Hostname(String)
+}
+
+impl DestinationMatcher {
+
+    pub fn any() -> Self {
+        DestinationMatcher {
+            host: HostMatcher::Any,
+            ports: 0..=u16::MAX
+        }
+    }
+
+    /// Matches resolved addresses, so a hostname pointing into the range is matched as well.
+    pub fn cidr(cidr: &str) -> Result<Self, SecureLinkError> {
+        Ok(DestinationMatcher {
+            host: HostMatcher::Cidr(cidr.parse()?),
+            ports: 0..=u16::MAX
+        })
+    }
+
+    /// Case-insensitive hostname as sent by the server, `*.example.com` matches any subdomain.
+    pub fn hostname(pattern: &str) -> Self {
+        DestinationMatcher {
+            host: HostMatcher::Hostname(pattern.to_ascii_lowercase()),
+            ports: 0..=u16::MAX
+        }
+    }
+
+    pub fn ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        self.ports(port..=port)
+    }
+
+    fn matches(&self, destination: &ProxyDestination, address: SocketAddr) -> bool {
+
+        if !self.ports.contains(&address.port()) {
+            return false;
+        }
+
+        match &self.host {
+            HostMatcher::Any => true,
+            HostMatcher::Cidr(cidr) => cidr.contains(address.ip()),
+            HostMatcher::Hostname(pattern) => hostname_matches(pattern, &destination.ip)
+        }
+    }
+
+    /// Same outcome as `matches` on any address of the destination, `None` if that depends on
+    /// the address.
+    fn matches_by_name(&self, destination: &ProxyDestination) -> Option<bool> {
+
+        if !self.ports.contains(&destination.port) {
+            return Some(false);
+        }
+
+        match &self.host {
+            HostMatcher::Any => Some(true),
+            // An IP literal resolves to itself
+            HostMatcher::Cidr(cidr) => IpAddr::from_str(&destination.ip).ok().map(|ip| cidr.contains(ip)),
+            HostMatcher::Hostname(pattern) => Some(hostname_matches(pattern, &destination.ip))
+        }
+    }
+}
+
+fn hostname_matches(pattern: &str, hostname: &str) -> bool {
+
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => hostname.len() > suffix.len() && hostname.ends_with(suffix)
+            && hostname.as_bytes()[hostname.len() - suffix.len() - 1] == b'.',
+        None => pattern == "*" || pattern == hostname
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix_len: u8
+}
+
+impl IpCidr {
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 addresses are matched against IPv4 networks
+        let ip = if self.network.is_ipv4() { ip.to_canonical() } else { ip };
+
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = SecureLinkError;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+
+        let invalid = || SecureLinkError::InvalidDestinationRule(format!("invalid CIDR: {}", cidr));
+
+        let (network, prefix_len) = match cidr.split_once('/') {
+            Some((network, prefix_len)) => {
+                let network = IpAddr::from_str(network).map_err(|_| invalid())?;
+                let prefix_len = prefix_len.parse::<u8>().map_err(|_| invalid())?;
+                (network, prefix_len)
+            }
+            None => {
+                let network = IpAddr::from_str(cidr).map_err(|_| invalid())?;
+                let prefix_len = if network.is_ipv4() { 32 } else { 128 };
+                (network, prefix_len)
+            }
+        };
+
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+
+        Ok(IpCidr { network, prefix_len })
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+
+    let full_bytes = (prefix_len / 8) as usize;
+    let remaining_bits = prefix_len % 8;
+
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destination(host: &str, port: u16) -> ProxyDestination {
+        ProxyDestination { ip: host.to_string(), port }
+    }
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    fn cidr(cidr: &str) -> IpCidr {
+        cidr.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        assert!(cidr("10.1.0.0/16").contains("10.1.255.1".parse().unwrap()));
+        assert!(!cidr("10.1.0.0/16").contains("10.2.0.1".parse().unwrap()));
+        assert!(cidr("10.0.0.0/9").contains("10.127.0.1".parse().unwrap()));
+        assert!(!cidr("10.0.0.0/9").contains("10.128.0.1".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains("203.0.113.7".parse().unwrap()));
+        assert!(cidr("192.0.2.1/32").contains("192.0.2.1".parse().unwrap()));
+        assert!(!cidr("192.0.2.1/32").contains("192.0.2.2".parse().unwrap()));
+        assert!(cidr("192.0.2.1").contains("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        assert!(cidr("2001:db8::/32").contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr("2001:db8::/32").contains("2001:db9::1".parse().unwrap()));
+        assert!(cidr("::/0").contains("fe80::1".parse().unwrap()));
+        assert!(cidr("2001:db8::1/128").contains("2001:db8::1".parse().unwrap()));
+        assert!(!cidr("2001:db8::1/128").contains("2001:db8::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn address_families_do_not_mix() {
+        assert!(!cidr("0.0.0.0/0").contains("2001:db8::1".parse().unwrap()));
+        assert!(!cidr("::/0").contains("192.0.2.1".parse().unwrap()));
+        assert!(cidr("192.0.2.0/24").contains("::ffff:192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_prefixes_are_rejected() {
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0/8", "example.com/8", ""] {
+            assert!(invalid.parse::<IpCidr>().is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn wildcard_hostname_matches_subdomains_only() {
+        let matcher = DestinationMatcher::hostname("*.Example.com");
+        let any_address = address("192.0.2.1:443");
+
+        assert!(matcher.matches(&destination("db.example.com", 443), any_address));
+        assert!(matcher.matches(&destination("a.b.EXAMPLE.com.", 443), any_address));
+        assert!(!matcher.matches(&destination("example.com", 443), any_address));
+        assert!(!matcher.matches(&destination("badexample.com", 443), any_address));
+    }
+
+    #[test]
+    fn exact_hostname_matches_only_itself() {
+        let matcher = DestinationMatcher::hostname("db.example.com");
+        let any_address = address("192.0.2.1:443");
+
+        assert!(matcher.matches(&destination("DB.example.com.", 443), any_address));
+        assert!(!matcher.matches(&destination("x.db.example.com", 443), any_address));
+        assert!(!matcher.matches(&destination("db.example.org", 443), any_address));
+    }
+
+    #[test]
+    fn port_ranges_are_inclusive() {
+        let matcher = DestinationMatcher::any().ports(8000..=8080);
+
+        assert!(matcher.matches(&destination("host", 8000), address("192.0.2.1:8000")));
+        assert!(matcher.matches(&destination("host", 8080), address("192.0.2.1:8080")));
+        assert!(!matcher.matches(&destination("host", 8081), address("192.0.2.1:8081")));
+        assert!(!matcher.matches(&destination("host", 7999), address("192.0.2.1:7999")));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = DestinationRules::deny_all()
+            .deny(DestinationMatcher::cidr("10.0.0.5/32").unwrap())
+            .allow(DestinationMatcher::cidr("10.0.0.0/8").unwrap())
+            .deny(DestinationMatcher::any());
+
+        assert_eq!(rules.evaluate(&destination("10.0.0.5", 22), address("10.0.0.5:22")), PolicyAction::Deny);
+        assert_eq!(rules.evaluate(&destination("10.0.0.6", 22), address("10.0.0.6:22")), PolicyAction::Allow);
+        assert_eq!(rules.evaluate(&destination("192.0.2.1", 22), address("192.0.2.1:22")), PolicyAction::Deny);
+    }
+
+    #[test]
+    fn default_action_applies_without_match() {
+        let allowing = DestinationRules::allow_all().deny(DestinationMatcher::any().port(25));
+        let denying = DestinationRules::deny_all().allow(DestinationMatcher::any().port(443));
+
+        assert!(allowing.is_allowed(&destination("host", 22), address("192.0.2.1:22")));
+        assert!(!allowing.is_allowed(&destination("host", 25), address("192.0.2.1:25")));
+        assert!(!denying.is_allowed(&destination("host", 22), address("192.0.2.1:22")));
+        assert!(denying.is_allowed(&destination("host", 443), address("192.0.2.1:443")));
+    }
+
+    #[test]
+    fn hostname_rules_decide_without_resolving() {
+        let rules = DestinationRules::deny_all()
+            .deny(DestinationMatcher::hostname("admin.example.com"))
+            .allow(DestinationMatcher::hostname("*.example.com").port(443));
+
+        assert_eq!(rules.evaluate_by_name(&destination("admin.example.com", 443)), Some(PolicyAction::Deny));
+        assert_eq!(rules.evaluate_by_name(&destination("db.example.com", 443)), Some(PolicyAction::Allow));
+        assert_eq!(rules.evaluate_by_name(&destination("db.example.com", 22)), Some(PolicyAction::Deny));
+        assert_eq!(rules.evaluate_by_name(&destination("unresolvable.invalid", 443)), Some(PolicyAction::Deny));
+    }
+
+    #[test]
+    fn cidr_rules_need_addresses_of_hostnames_only() {
+        let rules = DestinationRules::allow_all()
+            .deny(DestinationMatcher::cidr("10.0.0.0/8").unwrap().port(22))
+            .deny(DestinationMatcher::cidr("192.168.0.0/16").unwrap());
+
+        assert_eq!(rules.evaluate_by_name(&destination("10.0.0.1", 22)), Some(PolicyAction::Deny));
+        assert_eq!(rules.evaluate_by_name(&destination("192.0.2.1", 22)), Some(PolicyAction::Allow));
+        assert_eq!(rules.evaluate_by_name(&destination("db.example.com", 443)), None);
+        assert!(AllowAllDestinations.is_allowed_by_name(&destination("unresolvable.invalid", 443)).unwrap());
+    }
+}
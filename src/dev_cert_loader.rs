@@ -1,10 +1,11 @@
+use std::time::{Duration, UNIX_EPOCH};
 use include_dir::{include_dir, Dir};
 use rustls::pki_types::CertificateDer;
 use rustls::RootCertStore;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
 use x509_parser::pem::Pem;
 use x509_parser::x509::X509Version;
+use crate::dev_cert_report::{DevCertificateEntry, DevCertificateReport, DevCertificateStatus};
+use crate::SecureLinkError;
 
 pub struct DevCertLoader;
 
@@ -14,72 +15,94 @@ static CERT_ENV : &str = "SECURE_LINK_CLIENT_DEV_CERT_PEM_CONTENT";
 
 impl DevCertLoader {
 
-    pub async fn load_dev_certs(root_cert_store: &mut RootCertStore) -> Result<(), anyhow::Error> {
-        
+    /// Adds every valid dev certificate and skips the others. Fails only if certificates were
+    /// found but none of them could be used, the report lists the reasons either way.
+    pub async fn load_dev_certs(root_cert_store: &mut RootCertStore) -> Result<DevCertificateReport, SecureLinkError> {
+
+        let mut report = DevCertificateReport::default();
+
         for entry in DEV_CERTS_DIR.entries() {
 
-            let file = entry.as_file().ok_or(anyhow::Error::msg("not a cert file"))?;
-            let certs = Self::load_dev_cert(file.contents()).await?;
-            
-            for cert in certs {
-                if let Err(e) = root_cert_store.add(cert) {
-                    log::warn!("Failed to add dev certificate: {}", e);
-                }
+            let source = entry.path().display().to_string();
+
+            match entry.as_file() {
+                Some(file) => Self::load_dev_cert(&source, file.contents(), root_cert_store, &mut report),
+                None => report.certificates.push(rejected(source, "not a cert file"))
             }
-            
-        }
-        
-        if let Ok(dev_cert_env_value) = std::env::var(CERT_ENV) {
 
-            let certs_from_env = Self::load_dev_cert(dev_cert_env_value.as_bytes()).await?;
+        }
 
-            for cert in certs_from_env {
-                if let Err(e) = root_cert_store.add(cert) {
-                    log::warn!("Failed to add dev certificate: {}", e);
-                }
-            }
+        if let Ok(dev_cert_env_value) = std::env::var(CERT_ENV) {
+            Self::load_dev_cert(CERT_ENV, dev_cert_env_value.as_bytes(), root_cert_store, &mut report);
+        }
 
+        for certificate in report.rejected() {
+            log::warn!("Rejected dev certificate {}: {:?}", certificate.source, certificate.status);
         }
-        
-        Ok(())
 
-    }
-    
-    pub async fn load_dev_cert_file(cert_path: &str, root_cert_store: &mut RootCertStore) -> Result<(), anyhow::Error> {
-        
-        let mut cert_file = File::open(cert_path).await?;
-        let mut data = Vec::new();
-        cert_file.read_to_end(&mut data).await?;
-        
-        let certs = Self::load_dev_cert(data.as_slice()).await?;
-
-        for cert in certs {
-            if let Err(e) = root_cert_store.add(cert) {
-                log::warn!("Failed to add dev certificate: {}", e);
-            }
+        if !report.certificates.is_empty() && report.accepted().next().is_none() {
+            return Err(SecureLinkError::DevCertificatesLoadingError(report));
         }
-        
-        Ok(())
-        
+
+        Ok(report)
+
     }
-    
-    async fn load_dev_cert(data: &[u8]) -> Result<Vec<CertificateDer>, anyhow::Error> {
-        
 
-        let der_certs: Vec<CertificateDer> =
-            Pem::iter_from_buffer(&data).map(|pem| {
+    fn load_dev_cert(source: &str, data: &[u8], root_cert_store: &mut RootCertStore, report: &mut DevCertificateReport) {
+
+        for (index, pem) in Pem::iter_from_buffer(data).enumerate() {
+
+            let source = format!("{}#{}", source, index);
+
+            let pem = match pem {
+                Ok(pem) => pem,
+                Err(err) => {
+                    // The rest of the buffer cannot be realigned to a block boundary
+                    report.certificates.push(rejected(source, &format!("reading PEM block failed: {}", err)));
+                    return;
+                }
+            };
+
+            let x509 = match pem.parse_x509() {
+                Ok(x509) => x509,
+                Err(err) => {
+                    report.certificates.push(rejected(source, &format!("X.509: decoding DER failed: {}", err)));
+                    continue;
+                }
+            };
+
+            let version = x509.tbs_certificate.version;
+            let not_after = x509.validity().not_after.timestamp();
 
-                let pem = pem.expect("Reading next PEM block failed");
-                let x509 = pem.parse_x509().expect("X.509: decoding DER failed");
+            let status = if version != X509Version::V3 {
+                DevCertificateStatus::Rejected(format!("unsupported X.509 version {}", version.0 + 1))
+            } else {
+                match root_cert_store.add(CertificateDer::from(pem.contents.clone())) {
+                    Ok(()) => DevCertificateStatus::Accepted,
+                    Err(err) => DevCertificateStatus::Rejected(err.to_string())
+                }
+            };
 
-                assert_eq!(x509.tbs_certificate.version, X509Version::V3);
+            report.certificates.push(DevCertificateEntry {
+                source,
+                subject: Some(x509.subject().to_string()),
+                not_after: u64::try_from(not_after).ok().map(|not_after| UNIX_EPOCH + Duration::from_secs(not_after)),
+                version: Some(version.0 + 1),
+                status
+            });
 
-                CertificateDer::from(pem.contents)
+        }
 
-            }).collect();
+    }
 
+}
 
-        Ok(der_certs)
+fn rejected(source: String, reason: &str) -> DevCertificateEntry {
+    DevCertificateEntry {
+        source,
+        subject: None,
+        not_after: None,
+        version: None,
+        status: DevCertificateStatus::Rejected(reason.to_string())
     }
-    
-}
\ No newline at end of file
+}
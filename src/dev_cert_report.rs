@@ -0,0 +1,51 @@
+use std::fmt;
+use std::time::SystemTime;
+
+/// Outcome of loading the dev certificates, one entry per PEM block that was found.
+#[derive(Debug, Clone, Default)]
+pub struct DevCertificateReport {
+    pub certificates: Vec<DevCertificateEntry>
+}
+
+#[derive(Debug, Clone)]
+pub struct DevCertificateEntry {
+    /// File in `dev_certs/` or the environment variable, followed by the block index.
+    pub source: String,
+    pub subject: Option<String>,
+    pub not_after: Option<SystemTime>,
+    /// X.509 version as written in certificates, i.e. 3 for v3.
+    pub version: Option<u32>,
+    pub status: DevCertificateStatus
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DevCertificateStatus {
+    Accepted,
+    Rejected(String)
+}
+
+impl DevCertificateReport {
+
+    pub fn accepted(&self) -> impl Iterator<Item = &DevCertificateEntry> {
+        self.certificates.iter().filter(|certificate| certificate.status == DevCertificateStatus::Accepted)
+    }
+
+    pub fn rejected(&self) -> impl Iterator<Item = &DevCertificateEntry> {
+        self.certificates.iter().filter(|certificate| certificate.status != DevCertificateStatus::Accepted)
+    }
+}
+
+impl fmt::Display for DevCertificateReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        write!(f, "{} of {} dev certificates accepted", self.accepted().count(), self.certificates.len())?;
+
+        for certificate in self.rejected() {
+            if let DevCertificateStatus::Rejected(reason) = &certificate.status {
+                write!(f, ", {} rejected: {}", certificate.source, reason)?;
+            }
+        }
+
+        Ok(())
+    }
+}
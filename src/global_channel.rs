@@ -1,17 +1,27 @@
 use std::net::{SocketAddr};
+use std::io::ErrorKind;
 use std::sync::{Arc, Mutex};
 use anyhow::anyhow;
-use log::{debug, error, info, warn};
-use rustls::ClientConfig;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf};
+use futures::future::{AbortHandle, Abortable};
+use log::{error, info, warn};
+use tokio::io::ReadHalf;
 use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
 use crate::cs_global_chanel_sender::CsGlobalChannelSender;
+use crate::health_check::{health_check_loop, Liveness, PendingHealthChecks};
+use crate::protocol::frame_codec::FrameCodec;
 use crate::protocol::global_channel_join_request::GlobalChannelJoinRequest;
 use crate::protocol::global_channel_join_response::GlobalChannelJoinResponse;
-use crate::protocol::global_channel_message::{CsGlobalChannelMessage, ProxyChannelOpenResponse, ProxyChannelOpenResponseResult, ScGlobalChannelMessage};
+use crate::protocol::protocol_version::{NegotiatedProtocol, FEATURE_EXTENDED_OPEN_RESULTS, FEATURE_HEALTH_CHECK_CORRELATION, FEATURE_PROXY_CHANNEL_CLOSE, FEATURE_UNSUPPORTED_MESSAGE_REPLY, SUPPORTED_FEATURES};
+use crate::protocol::global_channel_message::{CsGlobalChannelMessage, IncomingScGlobalChannelMessage, ProxyChannelCloseReason, ProxyChannelClosed, ProxyChannelOpenResponse, ProxyChannelOpenResponseResult, ProxyDestination, ScGlobalChannelMessage, UnsupportedMessage};
 use crate::protocol::global_channel_message::ScGlobalChannelMessage::ProxyChannelOpenRequest;
 use crate::proxy_channel::ProxyChannel;
+use crate::proxy_channel_registry::ProxyChannelRegistration;
+use crate::connector::BoxedDestinationStream;
+use crate::destination_policy::allowed_addresses;
+use crate::secure_link_config::SecureLinkConfig;
+use crate::secure_link_event::{DisconnectReason, SecureLinkEvent};
+use crate::shutdown::ShutdownSignal;
 use crate::SecureLinkError;
 use crate::tls_connect::connect_to_domain;
 
@@ -20,52 +30,55 @@ pub struct GlobalChannel {
     secure_link_server_socket_addr: SocketAddr,
     secure_link_server_domain: String,
     tls_stream: TlsStream<TcpStream>,
-    tls_config: Arc<ClientConfig>,
-    running_health_check_channel: Arc<Mutex<Option<tokio::sync::mpsc::Sender<()>>>>
+    config: Arc<SecureLinkConfig>,
+    protocol: NegotiatedProtocol,
+    pending_health_checks: Arc<Mutex<PendingHealthChecks>>
 }
 
 impl GlobalChannel {
 
-    pub async fn create_global_channel(secure_link_server_socket_addr: SocketAddr, secure_link_server_domain: String, tls_config: Arc<ClientConfig>, auth_token: String) -> Result<GlobalChannel, SecureLinkError> {
+    pub async fn create_global_channel(secure_link_server_socket_addrs: &[SocketAddr], secure_link_server_domain: String, config: Arc<SecureLinkConfig>, auth_token: String) -> Result<GlobalChannel, SecureLinkError> {
 
-        let mut tls_stream = 
+        // Proxy channels reuse the address that worked for the global channel
+        let (mut tls_stream, secure_link_server_socket_addr) =
             connect_to_domain(
-                tls_config.clone(), 
-                secure_link_server_socket_addr,
-                secure_link_server_domain.clone()
+                config.tls_config.clone(),
+                secure_link_server_socket_addrs,
+                secure_link_server_domain.clone(),
+                config.connect_timeout,
+                config.tls_handshake_timeout
             )
             .await
             .map_err(|err| { SecureLinkError::GlobalChannelConnectError(err.into()) })?;
         
-        let global_channel_join_request = GlobalChannelJoinRequest::new(auth_token);
-
-        let request_json =
-            serde_json::to_string(&global_channel_join_request)
-                .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })?;
-
-        let pdu_length = (request_json.len() as u32).to_be_bytes();
-
-        let mut global_channel_join_request_pdu= vec![0u8];
-
-        global_channel_join_request_pdu.extend_from_slice(&pdu_length);
-        global_channel_join_request_pdu.extend_from_slice(request_json.as_bytes());
+        let global_channel_join_request =
+            GlobalChannelJoinRequest::new(
+                auth_token,
+                config.max_protocol_version,
+                SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect()
+            );
 
-        tls_stream.write(&global_channel_join_request_pdu).await
-            .map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+        config.frame_codec.write_frame(&mut tls_stream, &global_channel_join_request).await?;
 
-        let _reserved = tls_stream.read_u8().await.map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
-        let length = tls_stream.read_u32().await.map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+        let channel_join_response: GlobalChannelJoinResponse =
+            config.frame_codec.read_frame_within_deadline(&mut tls_stream).await?;
 
-        let mut message = vec![0; length as usize];
+        match channel_join_response {
+            GlobalChannelJoinResponse::GlobalChannelJoinConfirmed(global_channel_join_confirmed) => {
 
-        tls_stream.read_exact(&mut message).await.map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+                let protocol =
+                    NegotiatedProtocol::negotiate(
+                        config.max_protocol_version,
+                        global_channel_join_confirmed.protocol_version,
+                        &global_channel_join_confirmed.features
+                    );
 
-        let channel_join_response =
-            serde_json::from_slice::<GlobalChannelJoinResponse>(&message)
-                .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })?;
+                info!("negotiated protocol version {} with features {:?}", protocol.version, protocol.features);
 
-        match channel_join_response {
-            GlobalChannelJoinResponse::GlobalChannelJoinConfirmed(global_channel_join_confirmed) => {
+                config.emit(SecureLinkEvent::GlobalChannelConnected {
+                    secure_link_session_id: global_channel_join_confirmed.secure_link_session_id.clone(),
+                    protocol: protocol.clone()
+                });
 
                 let global_channel =
                     GlobalChannel {
@@ -73,8 +86,9 @@ impl GlobalChannel {
                         secure_link_server_socket_addr,
                         secure_link_server_domain,
                         tls_stream,
-                        tls_config,
-                        running_health_check_channel: Arc::new(Mutex::new(None))
+                        config,
+                        protocol,
+                        pending_health_checks: Arc::new(Mutex::new(PendingHealthChecks::default()))
                     };
 
                 Ok(global_channel)
@@ -89,276 +103,436 @@ impl GlobalChannel {
     }
 
 
-    pub async fn run_message_loop(self) -> Result<(), SecureLinkError> {
+    pub async fn run_message_loop(self, shutdown_signal: ShutdownSignal) -> Result<(), SecureLinkError> {
 
-        let (mut tls_stream_reader, tls_stream_writer) = tokio::io::split(self.tls_stream);
+        let config = self.config.clone();
 
-        let (unrecoverable_error_in_channels_sender, mut unrecoverable_error_in_channels_receiver) = tokio::sync::mpsc::channel::<SecureLinkError>(1);
+        let result = self.process_messages(shutdown_signal).await;
+
+        config.current_global_channel_sender.clear();
+
+        let reason = match &result {
+            Ok(()) => DisconnectReason::Shutdown,
+            Err(SecureLinkError::ProtocolSerializationError(err)) => DisconnectReason::ProtocolError(format!("{:?}", err)),
+            Err(err) => DisconnectReason::ConnectionLost(format!("{:?}", err))
+        };
+
+        config.emit(SecureLinkEvent::GlobalChannelDisconnected { reason });
+
+        result
+
+    }
+
+    async fn process_messages(self, shutdown_signal: ShutdownSignal) -> Result<(), SecureLinkError> {
+
+        let (mut tls_stream_reader, tls_stream_writer) = tokio::io::split(self.tls_stream);
 
         let (health_check_failed_sender, mut health_check_receiver) = tokio::sync::mpsc::channel::<()>(1);
-        
-        let global_channel_sender = CsGlobalChannelSender::new(tls_stream_writer);
+
+        let (sc_global_channel_message_sender, mut sc_global_channel_message_receiver) =
+            tokio::sync::mpsc::channel::<Result<IncomingScGlobalChannelMessage, SecureLinkError>>(1);
+
+        let global_channel_sender = CsGlobalChannelSender::new(
+            tls_stream_writer,
+            self.config.frame_codec.with_protocol_version(self.protocol.version)
+        );
+
+        self.config.current_global_channel_sender.set(global_channel_sender.clone(), self.protocol.clone());
 
         let secure_link_server_socket_addr = self.secure_link_server_socket_addr;
         let secure_link_server_domain = self.secure_link_server_domain;
-        let tls_config = self.tls_config;
+        let config = self.config;
         let secure_link_session_id = self.secure_link_session_id;
-
-        let running_health_check_channel_clone = self.running_health_check_channel.clone();
+        let protocol = self.protocol;
+        let pending_health_checks = self.pending_health_checks;
+
+        let health_check_config = config.clone();
+        let pending_health_checks_clone = pending_health_checks.clone();
+        let liveness = Arc::new(Liveness::new());
+        let liveness_clone = liveness.clone();
+        let correlate_health_checks = protocol.supports(FEATURE_HEALTH_CHECK_CORRELATION);
         let global_channel_sender_clone = global_channel_sender.clone();
-        
-        tokio::spawn(async move {
-           
+
+        let health_check_task = tokio::spawn(async move {
+
             health_check_loop(
-                running_health_check_channel_clone,
+                health_check_config,
+                pending_health_checks_clone,
+                liveness_clone,
+                correlate_health_checks,
                 global_channel_sender_clone,
                 health_check_failed_sender
             ).await;
-            
+
         });
-        
-        loop {
 
-            let global_channel_message =
-                receive_next_sc_global_channel_message(
-                    &mut tls_stream_reader
-                ).await?;
-
-            let running_health_check_channel = self.running_health_check_channel.clone();
-            
-            let handle_sc_global_channel_message_future = handle_sc_global_channel_message(
-                global_channel_message,
-                &secure_link_server_socket_addr,
-                &secure_link_server_domain,
-                tls_config.clone(),
-                &secure_link_session_id,
-                &global_channel_sender,
-                &unrecoverable_error_in_channels_sender,
-                running_health_check_channel
-            );
+        // Frames are read in their own task so that select! below never cancels a partial read
+        let frame_codec = config.frame_codec;
 
+        let receive_task = tokio::spawn(async move {
 
-            tokio::select! {
-                
-                handle_sc_global_channel_result = handle_sc_global_channel_message_future => {
+            loop {
 
-                    match handle_sc_global_channel_result {
-                        Ok(_) => {}
-                        Err(err) => {
-                            return Err(err);
-                        }
-                    }
+                let receive_result = receive_next_sc_global_channel_message(&frame_codec, &mut tls_stream_reader).await;
+                let failed = receive_result.is_err();
 
+                if !failed {
+                    liveness.record_activity();
                 }
 
-                Some(unrecoverable_error_in_channel) = unrecoverable_error_in_channels_receiver.recv() => {
-                    return Err(unrecoverable_error_in_channel)
-                }
-                
-                Some(()) = health_check_receiver.recv() => {
-                    return Err(SecureLinkError::SecureLinkServerConnectionLost(
-                        anyhow!("health check failed").into())
-                    )
+                if sc_global_channel_message_sender.send(receive_result).await.is_err() || failed {
+                    return;
                 }
-                
             }
 
-        }
+        });
 
-        async fn health_check_loop(
-            running_health_check_channel: Arc<Mutex<Option<tokio::sync::mpsc::Sender<()>>>>,
-            global_channel_sender: CsGlobalChannelSender,
-            health_check_failed_sender: tokio::sync::mpsc::Sender<()>
-        ) {
-            use tokio::time::{interval, timeout, Duration};
+        let _background_tasks = AbortOnDrop(vec![health_check_task, receive_task]);
 
-            const HEALTH_CHECK_INTERVAL_SECS: u64 = 5;
-            const HEALTH_CHECK_TIMEOUT_SECS: u64 = 10;
+        let mut shutting_down = false;
 
-            let mut interval = interval(Duration::from_secs(HEALTH_CHECK_INTERVAL_SECS));
+        loop {
 
-            loop {
-                interval.tick().await;
+            tokio::select! {
 
-                debug!("Sending health check request");
+                Some(receive_result) = sc_global_channel_message_receiver.recv() => {
 
-                // Create a channel for this specific health check
-                let (response_sender, mut response_receiver) = tokio::sync::mpsc::channel::<()>(1);
+                    let global_channel_message = match receive_result? {
+                        IncomingScGlobalChannelMessage::Supported(global_channel_message) => global_channel_message,
+                        IncomingScGlobalChannelMessage::Unsupported { r#type } => {
 
-                // Store the response channel
-                {
-                    let mut guard = running_health_check_channel.lock().unwrap();
-                    *guard = Some(response_sender);
-                }
+                            warn!("ignoring unsupported global channel message type {}", r#type);
 
-                // Send health check request
-                if let Err(err) = global_channel_sender.send_cs_global_channel_message(
-                    CsGlobalChannelMessage::HealthCheckRequest
-                ).await {
-                    error!("Failed to send health check request: {}", err);
+                            if protocol.supports(FEATURE_UNSUPPORTED_MESSAGE_REPLY) {
+                                global_channel_sender.send_cs_global_channel_message(
+                                    CsGlobalChannelMessage::UnsupportedMessage(
+                                        UnsupportedMessage { message_type: r#type }
+                                    )
+                                ).await?;
+                            }
 
-                    // Clean up the channel
-                    {
-                        let mut guard = running_health_check_channel.lock().unwrap();
-                        *guard = None;
-                    }
+                            continue;
+                        }
+                    };
+
+                    handle_sc_global_channel_message(
+                        global_channel_message,
+                        &secure_link_server_socket_addr,
+                        &secure_link_server_domain,
+                        config.clone(),
+                        &secure_link_session_id,
+                        &global_channel_sender,
+                        &protocol,
+                        shutting_down,
+                        &pending_health_checks
+                    ).await?;
 
-                    // Signal health check failure
-                    let _ = health_check_failed_sender.send(()).await;
-                    return;
                 }
 
-                // Wait for response with timeout
-                match timeout(Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS), response_receiver.recv()).await {
-                    Ok(Some(())) => {
-                        debug!("Health check response received");
-                        // Clean up the channel
-                        {
-                            let mut guard = running_health_check_channel.lock().unwrap();
-                            *guard = None;
-                        }
-                    }
-                    Ok(None) => {
-                        error!("Health check channel closed unexpectedly");
-                        // Clean up the channel
-                        {
-                            let mut guard = running_health_check_channel.lock().unwrap();
-                            *guard = None;
-                        }
-                        // Signal health check failure
-                        let _ = health_check_failed_sender.send(()).await;
-                        return;
-                    }
-                    Err(_) => {
-                        error!("Health check timeout - no response received within {} seconds", HEALTH_CHECK_TIMEOUT_SECS);
+                Some(()) = health_check_receiver.recv() => {
+                    return Err(SecureLinkError::SecureLinkServerConnectionLost(
+                        anyhow!("health check failed").into())
+                    )
+                }
 
-                        // Clean up the channel
-                        {
-                            let mut guard = running_health_check_channel.lock().unwrap();
-                            *guard = None;
-                        }
+                // SecureLink drains the proxy channels, this global channel only turns new ones away
+                _ = shutdown_signal.requested(), if !shutting_down => {
+                    info!("shutdown requested, rejecting new proxy channels");
+                    shutting_down = true;
+                }
 
-                        // Signal health check failure
-                        let _ = health_check_failed_sender.send(()).await;
-                        return;
-                    }
+                _ = shutdown_signal.drained() => {
+                    break;
                 }
+
             }
+
+        }
+
+        if let Err(err) = global_channel_sender.send_cs_global_channel_message(
+            CsGlobalChannelMessage::ClientLeaving
+        ).await {
+            warn!("failed to notify secure link server about client leaving: {}", err);
         }
 
+        global_channel_sender.shutdown().await;
+
+        return Ok(());
+
+        #[allow(clippy::too_many_arguments)]
         async fn handle_sc_global_channel_message(
             global_channel_message: ScGlobalChannelMessage,
             secure_link_server_socket_addr: &SocketAddr,
             secure_link_server_domain: &str,
-            tls_config: Arc<ClientConfig>,
-            _secure_link_session_id: &str,
+            config: Arc<SecureLinkConfig>,
+            secure_link_session_id: &str,
             global_channel_sender: &CsGlobalChannelSender,
-            unrecoverable_error_in_channels_sender: &tokio::sync::mpsc::Sender<SecureLinkError>,
-            running_health_check_channel: Arc<Mutex<Option<tokio::sync::mpsc::Sender<()>>>>
+            protocol: &NegotiatedProtocol,
+            shutting_down: bool,
+            pending_health_checks: &Mutex<PendingHealthChecks>
         ) -> Result<(), SecureLinkError> {
 
             match global_channel_message {
 
-                ScGlobalChannelMessage::HealthCheckRequest => {
+                ScGlobalChannelMessage::HealthCheckRequest(health_check) => {
 
+                    // Echo id and timestamp so the server can correlate its own health checks
                     let _result = global_channel_sender.send_cs_global_channel_message(
-                        CsGlobalChannelMessage::HealthCheckResponse
+                        CsGlobalChannelMessage::HealthCheckResponse(health_check)
                     ).await;
-                    
+
                 }
 
-                ScGlobalChannelMessage::HealthCheckResponse => {
-                    
-                    let channel = {
-                        running_health_check_channel.lock().unwrap().take()
-                    };
-                    
-                    if let Some(running_health_check_channel) = channel {
-                        
-                        if let Err(err) = running_health_check_channel.send(()).await {
-                            warn!("failed to process health check result: {}", err);
-                        }
+                ScGlobalChannelMessage::HealthCheckResponse(health_check) => {
+                    pending_health_checks.lock().unwrap().complete(health_check.id);
+                }
+
+                ScGlobalChannelMessage::ProxyChannelCloseRequest(proxy_channel_close_request) => {
+
+                    let proxy_channel_id = proxy_channel_close_request.proxy_channel_id;
+
+                    if config.proxy_channel_registry.close(&proxy_channel_id, ProxyChannelCloseReason::ClosedByServer) {
+                        info!("closing proxy channel {} on server request", proxy_channel_id);
+                    } else {
+                        warn!("server asked to close unknown proxy channel {}", proxy_channel_id);
                     }
-                    
+
                 }
-                
+
                 ProxyChannelOpenRequest(proxy_channel_open_request) => {
 
                     let proxy_channel_id = proxy_channel_open_request.proxy_channel_id;
                     let destination = proxy_channel_open_request.destination;
+                    let extended_open_results = protocol.supports(FEATURE_EXTENDED_OPEN_RESULTS);
+
+                    config.emit(SecureLinkEvent::ProxyChannelOpenRequested {
+                        proxy_channel_id: proxy_channel_id.clone(),
+                        destination: destination.clone()
+                    });
 
-                    // Create destination address string that can handle both IP and DNS
-                    let destination_addr = format!("{}:{}", destination.ip, destination.port);
+                    if shutting_down {
 
-                    let secure_link_server_socket_addr = secure_link_server_socket_addr.clone();
-                    let tls_config = tls_config.clone();
+                        info!("rejecting proxy channel {} while shutting down", proxy_channel_id);
+
+                        send_proxy_channel_open_response(
+                            global_channel_sender,
+                            proxy_channel_id,
+                            ProxyChannelOpenResponseResult::ClientShuttingDown,
+                            extended_open_results
+                        ).await;
+
+                        return Ok(());
+                    }
+
+                    let Some(mut proxy_channel_permit) = config.proxy_channel_limiter.try_acquire(&destination) else {
+
+                        warn!("rejecting proxy channel {} to {:?}, client at capacity", proxy_channel_id, destination);
+
+                        send_proxy_channel_open_response(
+                            global_channel_sender,
+                            proxy_channel_id,
+                            ProxyChannelOpenResponseResult::ClientAtCapacity,
+                            extended_open_results
+                        ).await;
+
+                        return Ok(());
+                    };
+
+                    let secure_link_server_socket_addr = *secure_link_server_socket_addr;
+                    let config = config.clone();
                     let global_channel_sender = global_channel_sender.clone();
-                    let unrecoverable_error_in_channels_sender = unrecoverable_error_in_channels_sender.clone();
                     let secure_link_server_domain = secure_link_server_domain.to_string();
+                    let secure_link_session_id = secure_link_session_id.to_string();
+                    let channel_token = proxy_channel_open_request.channel_token;
 
-                    tokio::spawn(async move {
+                    let (abort_handle, abort_registration) = AbortHandle::new_pair();
 
-                        // TcpStream::connect can handle both IP addresses and DNS names
-                        match TcpStream::connect(&destination_addr).await {
+                    // Registered before connecting, so the server can close the channel in any state
+                    let Some((proxy_channel_registration, mut close_receiver)) =
+                        config.proxy_channel_registry.register(&proxy_channel_id, &secure_link_session_id, destination.clone(), abort_handle) else {
 
-                            Ok(dst_tcp_stream) => {
+                        warn!("rejecting proxy channel {}, a channel with this id is still running", proxy_channel_id);
 
-                                let proxy_channel_create_result =
-                                    ProxyChannel::create_proxy_channel_with_secure_link_server(
-                                        secure_link_server_socket_addr,
-                                        secure_link_server_domain,
-                                        tls_config,
-                                        dst_tcp_stream,
-                                        proxy_channel_open_request.channel_token
-                                    ).await;
+                        send_proxy_channel_open_response(
+                            &global_channel_sender,
+                            proxy_channel_id,
+                            ProxyChannelOpenResponseResult::DuplicateProxyChannelId,
+                            extended_open_results
+                        ).await;
 
-                                match proxy_channel_create_result {
+                        return Ok(());
+                    };
 
-                                    Ok(proxy_channel) => {
+                    tokio::spawn(Abortable::new(async move {
 
-                                        let proxy_channel_run_result =
-                                            proxy_channel.run_proxy_between_sender_and_secure_link_server().await;
+                        let open_result = tokio::select! {
 
-                                        match proxy_channel_run_result {
-                                            Ok(()) => {
-                                                info!("proxy channel down");
-                                            }
-                                            Err(err) => {
-                                                warn!("proxy channel down with error: {}", err);
-                                            }
-                                        }
+                            open_result = open_proxy_channel(
+                                &config,
+                                &destination,
+                                &proxy_channel_registration,
+                                secure_link_server_socket_addr,
+                                secure_link_server_domain,
+                                channel_token
+                            ) => open_result,
 
+                            Ok(reason) = &mut close_receiver => {
 
-                                    }
-                                    Err(err) => {
+                                info!("proxy channel {} closed before it joined: {:?}", proxy_channel_id, reason);
 
-                                        error!("SecureLinkServerConnectionLost in proxy channel: {}", err);
+                                let duration = proxy_channel_registration.elapsed();
 
-                                        let _result = unrecoverable_error_in_channels_sender.send(
-                                            SecureLinkError::SecureLinkServerConnectionLost(Box::new(err))
-                                        ).await;
+                                config.traffic_accounting.record_closed(proxy_channel_registration.into_record(0, 0, reason));
 
-                                    }
+                                notify_proxy_channel_closed(&config, ProxyChannelClosed {
+                                    proxy_channel_id: proxy_channel_id.clone(),
+                                    reason,
+                                    bytes_uploaded: 0,
+                                    bytes_downloaded: 0
+                                }).await;
+
+                                config.emit(SecureLinkEvent::ProxyChannelClosed {
+                                    proxy_channel_id,
+                                    bytes_uploaded: 0,
+                                    bytes_downloaded: 0,
+                                    duration,
+                                    reason,
+                                    error: None
+                                });
+
+                                return;
+                            }
+
+                        };
+
+                        let proxy_channel = match open_result {
+
+                            Ok(proxy_channel) => proxy_channel,
+
+                            Err(ProxyChannelOpenError::Destination(err)) => {
+
+                                let result = match err.kind() {
+                                    ErrorKind::PermissionDenied => ProxyChannelOpenResponseResult::BadDestinationAddress,
+                                    _ => ProxyChannelOpenResponseResult::CouldNotReachDestination
                                 };
 
+                                config.emit(SecureLinkEvent::DestinationConnectFailed {
+                                    proxy_channel_id: proxy_channel_id.clone(),
+                                    destination,
+                                    error: err.to_string()
+                                });
+
+                                send_proxy_channel_open_response(
+                                    &global_channel_sender,
+                                    proxy_channel_id,
+                                    result,
+                                    extended_open_results
+                                ).await;
+
+                                warn!("failed to connect to requested dst {:?}", err);
+
+                                config.traffic_accounting.record_closed(
+                                    proxy_channel_registration.into_record(0, 0, ProxyChannelCloseReason::Error)
+                                );
+
+                                return;
                             }
-                            Err(err) => {
-
-                                let _result = global_channel_sender.send_cs_global_channel_message(
-                                    CsGlobalChannelMessage::ProxyChannelOpenResponse(
-                                        ProxyChannelOpenResponse {
-                                            proxy_channel_id,
-                                            result: ProxyChannelOpenResponseResult::CouldNotReachDestination
-                                        }
-                                    )
+
+                            Err(ProxyChannelOpenError::Join(err)) => {
+
+                                // Only this channel is lost, the global channel keeps serving the others
+                                error!("proxy channel {} failed to join secure link server: {}", proxy_channel_id, err);
+
+                                config.emit(SecureLinkEvent::ProxyChannelJoinFailed {
+                                    proxy_channel_id: proxy_channel_id.clone(),
+                                    error: format!("{:?}", err)
+                                });
+
+                                send_proxy_channel_open_response(
+                                    &global_channel_sender,
+                                    proxy_channel_id,
+                                    ProxyChannelOpenResponseResult::JoinFailed,
+                                    extended_open_results
                                 ).await;
 
-                                warn!("failed to connect to requested dst {:?}", err)
+                                config.traffic_accounting.record_closed(
+                                    proxy_channel_registration.into_record(0, 0, ProxyChannelCloseReason::Error)
+                                );
 
+                                return;
                             }
-                        }
+                        };
 
-                    });
+                        proxy_channel_permit.opened();
+
+                        let proxy_channel = proxy_channel.with_rate_limits(
+                            config.rate_limiters.for_channel(&destination)
+                        );
+
+                        send_proxy_channel_open_response(
+                            &global_channel_sender,
+                            proxy_channel_id.clone(),
+                            ProxyChannelOpenResponseResult::Ok,
+                            extended_open_results
+                        ).await;
+
+                        config.emit(SecureLinkEvent::ProxyChannelJoined {
+                            proxy_channel_id: proxy_channel_id.clone()
+                        });
+
+                        let proxy_channel_counters = proxy_channel.counters();
+
+                        proxy_channel_registration.active(proxy_channel_counters.clone());
+
+                        // Dropping the proxy future closes both streams
+                        let (reason, error) = tokio::select! {
+
+                            proxy_channel_run_result = proxy_channel.run_proxy_between_sender_and_secure_link_server() => {
+                                match proxy_channel_run_result {
+                                    Ok(reason) => {
+                                        info!("proxy channel down: {:?}", reason);
+                                        (reason, None)
+                                    }
+                                    Err(err) => {
+                                        warn!("proxy channel down with error: {}", err);
+                                        (ProxyChannelCloseReason::Error, Some(format!("{:?}", err)))
+                                    }
+                                }
+                            }
+
+                            Ok(reason) = &mut close_receiver => {
+                                info!("proxy channel {} closed: {:?}", proxy_channel_id, reason);
+                                (reason, None)
+                            }
+
+                        };
+
+                        let duration = proxy_channel_registration.elapsed();
+
+                        // Unregisters first so snapshots never count the channel twice
+                        config.traffic_accounting.record_closed(proxy_channel_registration.into_record(
+                            proxy_channel_counters.bytes_uploaded(),
+                            proxy_channel_counters.bytes_downloaded(),
+                            reason
+                        ));
+
+                        notify_proxy_channel_closed(&config, ProxyChannelClosed {
+                            proxy_channel_id: proxy_channel_id.clone(),
+                            reason,
+                            bytes_uploaded: proxy_channel_counters.bytes_uploaded(),
+                            bytes_downloaded: proxy_channel_counters.bytes_downloaded()
+                        }).await;
+
+                        config.emit(SecureLinkEvent::ProxyChannelClosed {
+                            proxy_channel_id,
+                            bytes_uploaded: proxy_channel_counters.bytes_uploaded(),
+                            bytes_downloaded: proxy_channel_counters.bytes_downloaded(),
+                            duration,
+                            reason,
+                            error
+                        });
+
+                    }, abort_registration));
 
                 }
             }
@@ -367,24 +541,102 @@ impl GlobalChannel {
 
         }
 
-        async fn receive_next_sc_global_channel_message(tls_stream_reader: &mut ReadHalf<TlsStream<TcpStream>>) -> Result<ScGlobalChannelMessage, SecureLinkError>{
+        async fn open_proxy_channel(
+            config: &Arc<SecureLinkConfig>,
+            destination: &ProxyDestination,
+            proxy_channel_registration: &ProxyChannelRegistration,
+            secure_link_server_socket_addr: SocketAddr,
+            secure_link_server_domain: String,
+            channel_token: String
+        ) -> Result<ProxyChannel<BoxedDestinationStream>, ProxyChannelOpenError> {
+
+            let destination_stream = connect_to_destination(config, destination).await
+                .map_err(ProxyChannelOpenError::Destination)?;
+
+            proxy_channel_registration.joining();
+
+            ProxyChannel::create_proxy_channel_with_secure_link_server(
+                secure_link_server_socket_addr,
+                secure_link_server_domain,
+                config.clone(),
+                destination_stream,
+                channel_token
+            ).await
+                .map_err(ProxyChannelOpenError::Join)
+        }
+
+        /// Goes through the global channel that is connected now, the one the proxy channel was
+        /// opened on may have been replaced by a reconnect in the meantime.
+        async fn notify_proxy_channel_closed(config: &SecureLinkConfig, proxy_channel_closed: ProxyChannelClosed) {
 
-            let _reserved = tls_stream_reader.read_u8().await.map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
-            let length = tls_stream_reader.read_u32().await.map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+            match config.current_global_channel_sender.get() {
+                Some((global_channel_sender, protocol)) if protocol.supports(FEATURE_PROXY_CHANNEL_CLOSE) => {
+                    let _result = global_channel_sender.send_cs_global_channel_message(
+                        CsGlobalChannelMessage::ProxyChannelClosed(proxy_channel_closed)
+                    ).await;
+                }
+                Some(_) => {}
+                None => {
+                    warn!("no global channel connected, server not notified that proxy channel {} closed", proxy_channel_closed.proxy_channel_id);
+                }
+            }
+        }
 
-            let mut global_channel_message_bytes = vec![0; length as usize];
+        async fn send_proxy_channel_open_response(
+            global_channel_sender: &CsGlobalChannelSender,
+            proxy_channel_id: String,
+            result: ProxyChannelOpenResponseResult,
+            extended_open_results: bool
+        ) {
 
-            tls_stream_reader.read_exact(&mut global_channel_message_bytes).await
-                .map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
-            
-            let global_channel_message =
-                serde_json::from_slice::<ScGlobalChannelMessage>(&global_channel_message_bytes)
-                    .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })?;
+            // Servers without the feature only know the results of the legacy protocol
+            let result = match result {
+                ProxyChannelOpenResponseResult::ClientShuttingDown
+                | ProxyChannelOpenResponseResult::JoinFailed
+                | ProxyChannelOpenResponseResult::ClientAtCapacity
+                | ProxyChannelOpenResponseResult::DuplicateProxyChannelId if !extended_open_results => {
+                    ProxyChannelOpenResponseResult::CouldNotReachDestination
+                }
+                result => result
+            };
+
+            let _result = global_channel_sender.send_cs_global_channel_message(
+                CsGlobalChannelMessage::ProxyChannelOpenResponse(
+                    ProxyChannelOpenResponse {
+                        proxy_channel_id,
+                        result
+                    }
+                )
+            ).await;
+        }
 
-            Ok(global_channel_message)
+        async fn connect_to_destination(config: &SecureLinkConfig, destination: &ProxyDestination) -> Result<BoxedDestinationStream, std::io::Error> {
+            let addresses = allowed_addresses(config.destination_policy.as_ref(), destination).await?;
+            config.connector.connect_boxed(destination, &addresses).await
+        }
 
+        async fn receive_next_sc_global_channel_message(
+            frame_codec: &FrameCodec,
+            tls_stream_reader: &mut ReadHalf<TlsStream<TcpStream>>
+        ) -> Result<IncomingScGlobalChannelMessage, SecureLinkError> {
+            frame_codec.read_frame(tls_stream_reader).await
         }
 
     }
 
-}
\ No newline at end of file
+}
+
+enum ProxyChannelOpenError {
+    Destination(std::io::Error),
+    Join(SecureLinkError)
+}
+
+struct AbortOnDrop(Vec<tokio::task::JoinHandle<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, error, warn};
+use tokio::sync::oneshot;
+use crate::cs_global_chanel_sender::CsGlobalChannelSender;
+use crate::protocol::global_channel_message::{CsGlobalChannelMessage, HealthCheck};
+use crate::secure_link_config::SecureLinkConfig;
+use crate::secure_link_event::SecureLinkEvent;
+
+/// Health check requests that are still waiting for their response, keyed by request id.
+#[derive(Default)]
+pub(crate) struct PendingHealthChecks {
+    next_id: u64,
+    pending: BTreeMap<u64, oneshot::Sender<()>>
+}
+
+impl PendingHealthChecks {
+
+    fn register(&mut self) -> (u64, oneshot::Receiver<()>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.pending.insert(id, response_sender);
+
+        (id, response_receiver)
+    }
+
+    fn forget(&mut self, id: u64) {
+        self.pending.remove(&id);
+    }
+
+    /// Servers without request ids answer in order, so an uncorrelated response completes the
+    /// oldest pending request.
+    pub fn complete(&mut self, id: Option<u64>) {
+
+        let response_sender = match id {
+            Some(id) => self.pending.remove(&id),
+            None => self.pending.pop_first().map(|(_, response_sender)| response_sender)
+        };
+
+        match response_sender {
+            Some(response_sender) => {
+                let _ = response_sender.send(());
+            }
+            None => warn!("health check response {:?} does not match any pending request", id)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthCheckStats {
+    pub samples: u64,
+    pub last: Duration,
+    pub min: Duration,
+    pub average: Duration,
+    /// Computed over the most recent samples only.
+    pub p99: Duration
+}
+
+pub(crate) struct RttRecorder(Mutex<RttRecorderInner>);
+
+struct RttRecorderInner {
+    samples: u64,
+    total: Duration,
+    last: Duration,
+    min: Duration,
+    recent: VecDeque<Duration>
+}
+
+impl RttRecorder {
+
+    const RECENT_SAMPLES: usize = 1000;
+
+    pub fn new() -> Self {
+        RttRecorder(Mutex::new(RttRecorderInner {
+            samples: 0,
+            total: Duration::ZERO,
+            last: Duration::ZERO,
+            min: Duration::MAX,
+            recent: VecDeque::with_capacity(Self::RECENT_SAMPLES)
+        }))
+    }
+
+    pub fn record(&self, rtt: Duration) {
+
+        let mut inner = self.0.lock().unwrap();
+
+        inner.samples += 1;
+        inner.total += rtt;
+        inner.last = rtt;
+        inner.min = inner.min.min(rtt);
+
+        if inner.recent.len() == Self::RECENT_SAMPLES {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(rtt);
+    }
+
+    pub fn stats(&self) -> Option<HealthCheckStats> {
+
+        let inner = self.0.lock().unwrap();
+
+        if inner.samples == 0 {
+            return None;
+        }
+
+        let mut recent: Vec<Duration> = inner.recent.iter().copied().collect();
+        recent.sort();
+
+        let p99_index = ((recent.len() as f64 * 0.99).ceil() as usize).clamp(1, recent.len()) - 1;
+
+        Some(HealthCheckStats {
+            samples: inner.samples,
+            last: inner.last,
+            min: inner.min,
+            average: inner.total / inner.samples as u32,
+            p99: recent[p99_index]
+        })
+    }
+}
+
+/// Time of the last PDU received on the global channel, any of them proves the server is alive.
+pub(crate) struct Liveness(Mutex<Instant>);
+
+impl Liveness {
+
+    pub fn new() -> Self {
+        Liveness(Mutex::new(Instant::now()))
+    }
+
+    pub fn record_activity(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+pub(crate) async fn health_check_loop(
+    config: Arc<SecureLinkConfig>,
+    pending_health_checks: Arc<Mutex<PendingHealthChecks>>,
+    liveness: Arc<Liveness>,
+    correlate_health_checks: bool,
+    global_channel_sender: CsGlobalChannelSender,
+    health_check_failed_sender: tokio::sync::mpsc::Sender<()>
+) {
+    use tokio::time::{sleep_until, timeout};
+
+    let health_check_timeout = config.health_check_timeout;
+    let health_check_idle_period = config.health_check_idle_period;
+
+    let mut missed_health_checks = 0;
+
+    loop {
+
+        // Only ping once the server has been quiet for a full idle period
+        loop {
+            let idle_deadline = liveness.last_activity() + health_check_idle_period;
+
+            if Instant::now() >= idle_deadline {
+                break;
+            }
+
+            sleep_until(idle_deadline.into()).await;
+        }
+
+        let (id, response_receiver) = pending_health_checks.lock().unwrap().register();
+
+        debug!("Sending health check request {}", id);
+
+        let health_check = match correlate_health_checks {
+            true => HealthCheck { id: Some(id), timestamp_ms: Some(unix_timestamp_ms()) },
+            false => HealthCheck::default()
+        };
+
+        let health_check_started_at = Instant::now();
+
+        // Send health check request
+        if let Err(err) = global_channel_sender.send_cs_global_channel_message(
+            CsGlobalChannelMessage::HealthCheckRequest(health_check)
+        ).await {
+            error!("Failed to send health check request: {}", err);
+
+            config.emit(SecureLinkEvent::HealthCheckFailed {
+                reason: format!("failed to send health check request: {:?}", err)
+            });
+
+            pending_health_checks.lock().unwrap().forget(id);
+
+            // Signal health check failure
+            let _ = health_check_failed_sender.send(()).await;
+            return;
+        }
+
+        // Wait for response with timeout
+        match timeout(health_check_timeout, response_receiver).await {
+            Ok(Ok(())) => {
+                let rtt = health_check_started_at.elapsed();
+
+                debug!("Health check response {} received in {:?}", id, rtt);
+
+                missed_health_checks = 0;
+
+                config.health_check_rtt.record(rtt);
+                config.emit(SecureLinkEvent::HealthCheckSucceeded { rtt });
+            }
+            Ok(Err(_)) => {
+                error!("Health check channel closed unexpectedly");
+
+                config.emit(SecureLinkEvent::HealthCheckFailed {
+                    reason: "health check channel closed unexpectedly".to_string()
+                });
+
+                // Signal health check failure
+                let _ = health_check_failed_sender.send(()).await;
+                return;
+            }
+            Err(_) => {
+                pending_health_checks.lock().unwrap().forget(id);
+
+                if liveness.last_activity() > health_check_started_at {
+                    debug!("Health check {} timed out but other traffic was received meanwhile", id);
+                    missed_health_checks = 0;
+                    continue;
+                }
+
+                missed_health_checks += 1;
+
+                config.emit(SecureLinkEvent::HealthCheckFailed {
+                    reason: format!(
+                        "no response received within {:?} ({} of {} allowed misses)",
+                        health_check_timeout, missed_health_checks, config.health_check_max_misses
+                    )
+                });
+
+                if missed_health_checks < config.health_check_max_misses {
+                    warn!("Health check timeout - no response received within {:?}, {} consecutive misses", health_check_timeout, missed_health_checks);
+                    continue;
+                }
+
+                error!("Health check timeout - {} consecutive health checks were not answered", missed_health_checks);
+
+                // Signal health check failure
+                let _ = health_check_failed_sender.send(()).await;
+                return;
+            }
+        }
+    }
+}
+
+pub(crate) fn unix_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -1,27 +1,91 @@
 mod protocol;
 mod global_channel;
 mod proxy_channel;
+mod proxy_channel_registry;
 mod tls_connect;
+mod client_certificate;
+mod spki_pinning;
+mod trust_anchors;
 #[cfg(feature = "load_dev_certs")]
 mod dev_cert_loader;
+mod dev_cert_report;
 mod secure_link;
+mod reconnect_policy;
+mod secure_link_builder;
+mod secure_link_config;
+mod secure_link_endpoint;
+mod destination_policy;
+mod connector;
+mod auth_token_provider;
+mod shutdown;
+mod health_check;
+mod secure_link_event;
+mod traffic_stats;
+mod rate_limit;
+mod proxy_channel_limits;
 
 mod cs_global_chanel_sender;
 
 #[derive(thiserror::Error, Debug)]
 pub enum SecureLinkError {
 
-    #[error("DevCertificatesLoadingError")] DevCertificatesLoadingError,
+    #[error("DevCertificatesLoadingError: {0}")] DevCertificatesLoadingError(DevCertificateReport),
     #[error("BadHostError")] BadHostError,
+    #[error("ResolveError")] ResolveError(Box<dyn std::error::Error + Send>),
     #[error("GlobalChannelConnectError")] GlobalChannelConnectError(Box<dyn std::error::Error + Send>),
     #[error("ProtocolSerializationError")] ProtocolSerializationError(Box<dyn std::error::Error + Send>),
     #[error("TlsStreamError")] TlsStreamError(Box<dyn std::error::Error + Send>),
     #[error("UnauthorizedError")] UnauthorizedError,
     #[error("SecureLinkServerConnectionLost")] SecureLinkServerConnectionLost(Box<dyn std::error::Error + Send>),
-    #[error("ProxyChannelJoinDenied")] ProxyChannelJoinDenied
+    #[error("ProxyChannelJoinDenied")] ProxyChannelJoinDenied,
+    #[error("ProxyChannelConnectError")] ProxyChannelConnectError(Box<dyn std::error::Error + Send>),
+    #[error("InvalidDestinationRule: {0}")] InvalidDestinationRule(String),
+    #[error("FrameError: {0}")] FrameError(FrameError),
+    #[error("ClientCertificateError: {0}")] ClientCertificateError(String),
+    #[error("InvalidSpkiPin: {0}")] InvalidSpkiPin(String),
+    #[error("ConflictingTlsOptions: {0}")] ConflictingTlsOptions(String),
+    #[error("TrustAnchorError: {0}")] TrustAnchorError(String),
+    #[error("AuthTokenError: {0}")] AuthTokenError(String),
+    #[error("AuthTokenConfigError: {0}")] AuthTokenConfigError(String)
+}
+
+impl SecureLinkError {
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            SecureLinkError::ResolveError(_)
+                | SecureLinkError::GlobalChannelConnectError(_)
+                | SecureLinkError::ProtocolSerializationError(_)
+                | SecureLinkError::TlsStreamError(_)
+                | SecureLinkError::SecureLinkServerConnectionLost(_)
+                | SecureLinkError::FrameError(_)
+                | SecureLinkError::AuthTokenError(_)
+        )
+    }
 }
 
 pub use secure_link::SecureLink;
+pub use protocol::frame_codec::FrameError;
+pub use protocol::protocol_version::{NegotiatedProtocol, CURRENT_PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION};
+pub use reconnect_policy::ReconnectPolicy;
+pub use secure_link_builder::SecureLinkBuilder;
+pub use client_certificate::ClientCertificate;
+pub use spki_pinning::SpkiPins;
+pub use trust_anchors::TrustAnchorSource;
+pub use dev_cert_report::{DevCertificateEntry, DevCertificateReport, DevCertificateStatus};
+pub use secure_link_endpoint::{EndpointSelection, SecureLinkEndpoint};
+pub use destination_policy::{AllowAllDestinations, DestinationMatcher, DestinationPolicy, DestinationRules, IpCidr, PolicyAction};
+pub use protocol::global_channel_message::{ProxyChannelCloseReason, ProxyDestination};
+pub use proxy_channel_registry::{ProxyChannelInfo, ProxyChannelState};
+pub use traffic_stats::{ProxyChannelRecord, TrafficStats, TrafficTotals};
+pub use rate_limit::BandwidthLimit;
+pub use proxy_channel_limits::ProxyChannelLimits;
+pub use shutdown::ShutdownHandle;
+pub use health_check::HealthCheckStats;
+pub use connector::{Connector, DestinationStream, TcpConnector};
+pub use auth_token_provider::{AuthTokenProvider, CommandAuthToken, EnvAuthToken, FileAuthToken, StaticAuthToken};
+pub use secure_link_event::{DisconnectReason, SecureLinkEvent};
 
 static_assertions::assert_impl_all!(SecureLink: Send, Sync);
 static_assertions::assert_impl_all!(SecureLinkError: Send);
@@ -0,0 +1,203 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use crate::protocol::protocol_version::LEGACY_PROTOCOL_VERSION;
+use crate::SecureLinkError;
+
+/// Encodes and decodes the `[version u8][length u32 BE][json]` PDUs shared by every channel.
+/// The first byte was reserved and is still written as 0 until a newer version is negotiated.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameCodec {
+    max_frame_size: u32,
+    read_timeout: Duration,
+    protocol_version: u8
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum FrameError {
+    #[error("frame of {length} bytes exceeds maximum of {max_frame_size} bytes")] TooLarge { length: u64, max_frame_size: u32 },
+    #[error("connection closed in the middle of a frame")] Truncated,
+    #[error("frame not completed within {0:?}")] ReadTimeout(Duration)
+}
+
+impl FrameCodec {
+
+    pub fn new(max_frame_size: u32, read_timeout: Duration) -> Self {
+        FrameCodec {
+            max_frame_size,
+            read_timeout,
+            protocol_version: LEGACY_PROTOCOL_VERSION
+        }
+    }
+
+    pub fn with_protocol_version(self, protocol_version: u8) -> Self {
+        FrameCodec { protocol_version, ..self }
+    }
+
+    pub fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, SecureLinkError> {
+
+        let message_json =
+            serde_json::to_vec(message)
+                .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })?;
+
+        if message_json.len() as u64 > self.max_frame_size as u64 {
+            return Err(SecureLinkError::FrameError(FrameError::TooLarge {
+                length: message_json.len() as u64,
+                max_frame_size: self.max_frame_size
+            }));
+        }
+
+        let pdu_length = (message_json.len() as u32).to_be_bytes();
+
+        let mut pdu = Vec::with_capacity(1 + pdu_length.len() + message_json.len());
+
+        pdu.push(self.protocol_version);
+        pdu.extend_from_slice(&pdu_length);
+        pdu.extend_from_slice(&message_json);
+
+        Ok(pdu)
+    }
+
+    pub async fn write_frame<W, T>(&self, writer: &mut W, message: &T) -> Result<(), SecureLinkError>
+    where
+        W: AsyncWrite + Unpin,
+        T: Serialize
+    {
+        let pdu = self.encode(message)?;
+
+        writer.write_all(&pdu).await
+            .map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+
+        Ok(())
+    }
+
+    /// Waits for the next frame as long as needed, the read deadline starts with its first byte.
+    pub async fn read_frame<R, T>(&self, reader: &mut R) -> Result<T, SecureLinkError>
+    where
+        R: AsyncRead + Unpin,
+        T: DeserializeOwned
+    {
+        let _protocol_version = reader.read_u8().await.map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+
+        timeout(self.read_timeout, self.read_frame_body(reader)).await
+            .map_err(|_| SecureLinkError::FrameError(FrameError::ReadTimeout(self.read_timeout)))?
+    }
+
+    /// Reads a frame that is expected right away, e.g. a join response.
+    pub async fn read_frame_within_deadline<R, T>(&self, reader: &mut R) -> Result<T, SecureLinkError>
+    where
+        R: AsyncRead + Unpin,
+        T: DeserializeOwned
+    {
+        timeout(self.read_timeout, self.read_frame(reader)).await
+            .map_err(|_| SecureLinkError::FrameError(FrameError::ReadTimeout(self.read_timeout)))?
+    }
+
+    async fn read_frame_body<R, T>(&self, reader: &mut R) -> Result<T, SecureLinkError>
+    where
+        R: AsyncRead + Unpin,
+        T: DeserializeOwned
+    {
+        let length = reader.read_u32().await.map_err(map_frame_read_error)?;
+
+        if length > self.max_frame_size {
+            return Err(SecureLinkError::FrameError(FrameError::TooLarge {
+                length: length as u64,
+                max_frame_size: self.max_frame_size
+            }));
+        }
+
+        let mut message = vec![0; length as usize];
+
+        reader.read_exact(&mut message).await.map_err(map_frame_read_error)?;
+
+        serde_json::from_slice::<T>(&message)
+            .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })
+    }
+}
+
+fn map_frame_read_error(err: std::io::Error) -> SecureLinkError {
+    match err.kind() {
+        ErrorKind::UnexpectedEof => SecureLinkError::FrameError(FrameError::Truncated),
+        _ => SecureLinkError::TlsStreamError(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use tokio::io::duplex;
+
+    const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn codec() -> FrameCodec {
+        FrameCodec::new(1024, READ_TIMEOUT)
+    }
+
+    fn header(length: u32) -> Vec<u8> {
+        let mut header = vec![LEGACY_PROTOCOL_VERSION];
+        header.extend_from_slice(&length.to_be_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn oversized_length_is_rejected_before_reading_the_body() {
+        let (mut writer, mut reader) = duplex(64);
+
+        // Only the header is sent, waiting for a body would run into the read timeout instead
+        writer.write_all(&header(u32::MAX)).await.unwrap();
+
+        let result: Result<Value, _> = codec().read_frame(&mut reader).await;
+
+        assert!(matches!(
+            result,
+            Err(SecureLinkError::FrameError(FrameError::TooLarge { length, max_frame_size: 1024 })) if length == u32::MAX as u64
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_reported() {
+        let (mut writer, mut reader) = duplex(64);
+
+        writer.write_all(&header(10)).await.unwrap();
+        writer.write_all(b"{\"a\"").await.unwrap();
+        drop(writer);
+
+        let result: Result<Value, _> = codec().read_frame(&mut reader).await;
+
+        assert!(matches!(result, Err(SecureLinkError::FrameError(FrameError::Truncated))));
+    }
+
+    #[tokio::test]
+    async fn stalled_body_times_out() {
+        let (mut writer, mut reader) = duplex(64);
+
+        writer.write_all(&header(10)).await.unwrap();
+        writer.write_all(b"{\"a\"").await.unwrap();
+
+        let result: Result<Value, _> = codec().read_frame(&mut reader).await;
+
+        assert!(matches!(result, Err(SecureLinkError::FrameError(FrameError::ReadTimeout(READ_TIMEOUT)))));
+        drop(writer);
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_with_the_protocol_version() {
+        let (mut writer, mut reader) = duplex(1024);
+        let codec = codec().with_protocol_version(1);
+        let message = json!({ "type": "health_check_request", "id": 7 });
+
+        let pdu = codec.encode(&message).unwrap();
+        assert_eq!(pdu[0], 1);
+        assert_eq!(u32::from_be_bytes(pdu[1..5].try_into().unwrap()) as usize, pdu.len() - 5);
+
+        codec.write_frame(&mut writer, &message).await.unwrap();
+        let decoded: Value = codec.read_frame(&mut reader).await.unwrap();
+
+        assert_eq!(decoded, message);
+    }
+}
@@ -3,16 +3,20 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GlobalChannelJoinRequest {
     pub r#type: String,
-    pub auth_token: String
+    pub auth_token: String,
+    pub protocol_version: u8,
+    pub features: Vec<String>
 }
 
 impl GlobalChannelJoinRequest {
     const TYPE: &'static str = "global_channel_join_request";
 
-    pub(crate) fn new(auth_token: String) -> Self {
+    pub(crate) fn new(auth_token: String, protocol_version: u8, features: Vec<String>) -> Self {
         GlobalChannelJoinRequest {
             r#type: Self::TYPE.to_string(),
-            auth_token
+            auth_token,
+            protocol_version,
+            features
         }
     }
-}
\ No newline at end of file
+}
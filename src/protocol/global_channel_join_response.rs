@@ -12,7 +12,12 @@ pub enum GlobalChannelJoinResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GlobalChannelJoinConfirmed { 
-    pub secure_link_session_id: String
+    pub secure_link_session_id: String,
+    // Absent for servers that predate version negotiation
+    #[serde(default)]
+    pub protocol_version: u8,
+    #[serde(default)]
+    pub features: Vec<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
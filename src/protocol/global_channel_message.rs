@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -6,17 +7,65 @@ pub enum ScGlobalChannelMessage {
     #[serde(rename = "proxy_channel_open_request")]
     ProxyChannelOpenRequest(ProxyChannelOpenRequest),
     #[serde(rename = "health_check_request")]
-    HealthCheckRequest,
+    HealthCheckRequest(HealthCheck),
     #[serde(rename = "health_check_response")]
-    HealthCheckResponse
+    HealthCheckResponse(HealthCheck),
+    #[serde(rename = "proxy_channel_close_request")]
+    ProxyChannelCloseRequest(ProxyChannelCloseRequest)
 }
+/// Both fields are omitted when talking to servers without health check correlation.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HealthCheck {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_ms: Option<u64>
+}
+
+/// Messages with a `type` this client does not know end up as `Unsupported` instead of failing
+/// the whole session. A known `type` with a malformed body is a serialization error.
+#[derive(Debug)]
+pub enum IncomingScGlobalChannelMessage {
+    Supported(ScGlobalChannelMessage),
+    Unsupported {
+        r#type: String
+    }
+}
+
+/// Every `type` of `ScGlobalChannelMessage`.
+const SC_GLOBAL_CHANNEL_MESSAGE_TYPES: &[&str] = &[
+    "proxy_channel_open_request",
+    "health_check_request",
+    "health_check_response",
+    "proxy_channel_close_request"
+];
+
+impl<'de> Deserialize<'de> for IncomingScGlobalChannelMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+
+        let message = serde_json::Value::deserialize(deserializer)?;
+
+        let message_type = message.get("type")
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| D::Error::missing_field("type"))?;
+
+        if !SC_GLOBAL_CHANNEL_MESSAGE_TYPES.contains(&message_type) {
+            return Ok(IncomingScGlobalChannelMessage::Unsupported { r#type: message_type.to_string() });
+        }
+
+        ScGlobalChannelMessage::deserialize(message)
+            .map(IncomingScGlobalChannelMessage::Supported)
+            .map_err(D::Error::custom)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProxyChannelOpenRequest {
     pub proxy_channel_id: String,
     pub channel_token: String,
     pub destination: ProxyDestination
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ProxyDestination {
     pub ip: String,
     pub port: u16
@@ -28,9 +77,61 @@ pub enum CsGlobalChannelMessage {
     #[serde(rename = "proxy_channel_open_response")]
     ProxyChannelOpenResponse(ProxyChannelOpenResponse),
     #[serde(rename = "health_check_request")]
-    HealthCheckRequest,
+    HealthCheckRequest(HealthCheck),
     #[serde(rename = "health_check_response")]
-    HealthCheckResponse
+    HealthCheckResponse(HealthCheck),
+    #[serde(rename = "client_leaving")]
+    ClientLeaving,
+    #[serde(rename = "unsupported_message")]
+    UnsupportedMessage(UnsupportedMessage),
+    #[serde(rename = "proxy_channel_closed")]
+    ProxyChannelClosed(ProxyChannelClosed)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnsupportedMessage {
+    pub message_type: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProxyChannelCloseRequest {
+    pub proxy_channel_id: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProxyChannelClosed {
+    pub proxy_channel_id: String,
+    pub reason: ProxyChannelCloseReason,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ProxyChannelCloseReason {
+    /// Both sides finished sending.
+    #[serde(rename = "completed")]
+    Completed,
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "closed_by_server")]
+    ClosedByServer,
+    /// No data moved in either direction for the configured idle timeout.
+    #[serde(rename = "idle_timeout")]
+    IdleTimeout,
+    #[serde(rename = "max_lifetime_exceeded")]
+    MaxLifetimeExceeded,
+    /// One direction finished and the other one did not within the linger timeout.
+    #[serde(rename = "linger_timeout")]
+    LingerTimeout,
+    /// A peer reset the connection.
+    #[serde(rename = "reset")]
+    Reset,
+    /// The TLS stream ended without close_notify, the data received last may be incomplete.
+    #[serde(rename = "truncated")]
+    Truncated,
+    /// Still running when the shutdown drain timeout ran out.
+    #[serde(rename = "shutdown_timeout")]
+    ShutdownTimeout
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,6 +140,8 @@ pub struct ProxyChannelOpenResponse {
     pub result: ProxyChannelOpenResponseResult
 }
 
+/// Results after `could_not_reach_destination` are only sent to servers that acknowledged
+/// `extended_open_results`, the others get `could_not_reach_destination` instead.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ProxyChannelOpenResponseResult {
     #[serde(rename = "ok")]
@@ -46,5 +149,16 @@ pub enum ProxyChannelOpenResponseResult {
     #[serde(rename = "bad_destination_address")]
     BadDestinationAddress,
     #[serde(rename = "could_not_reach_destination")]
-    CouldNotReachDestination
+    CouldNotReachDestination,
+    #[serde(rename = "client_shutting_down")]
+    ClientShuttingDown,
+    /// The destination was reached but the proxy channel could not join the secure link server.
+    #[serde(rename = "join_failed")]
+    JoinFailed,
+    /// A configured cap on concurrent proxy channels is reached, nothing was attempted.
+    #[serde(rename = "client_at_capacity")]
+    ClientAtCapacity,
+    /// A proxy channel with the same id is still running, it is left untouched.
+    #[serde(rename = "duplicate_proxy_channel_id")]
+    DuplicateProxyChannelId
 }
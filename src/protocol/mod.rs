@@ -1,5 +1,7 @@
+pub mod frame_codec;
 pub mod global_channel_join_request;
 pub mod global_channel_join_response;
 pub mod global_channel_message;
 pub mod proxy_channel_join_request;
-pub mod proxy_channel_join_response;
\ No newline at end of file
+pub mod proxy_channel_join_response;
+pub mod protocol_version;
\ No newline at end of file
@@ -0,0 +1,62 @@
+/// Version spoken by servers that do not take part in negotiation.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 0;
+
+pub const CURRENT_PROTOCOL_VERSION: u8 = 1;
+
+/// Optional protocol features this client can use when the server acknowledges them.
+pub(crate) const SUPPORTED_FEATURES: &[&str] = &[
+    FEATURE_UNSUPPORTED_MESSAGE_REPLY,
+    FEATURE_HEALTH_CHECK_CORRELATION,
+    FEATURE_PROXY_CHANNEL_CLOSE,
+    FEATURE_EXTENDED_OPEN_RESULTS
+];
+
+/// The client answers unknown SC message types with an `unsupported_message`.
+pub const FEATURE_UNSUPPORTED_MESSAGE_REPLY: &str = "unsupported_message_reply";
+
+/// Health check requests carry an id and timestamp that the response echoes.
+pub const FEATURE_HEALTH_CHECK_CORRELATION: &str = "health_check_correlation";
+
+/// The server may close proxy channels and wants to hear why a channel ended.
+pub const FEATURE_PROXY_CHANNEL_CLOSE: &str = "proxy_channel_close";
+
+/// Open responses may say why a channel was refused beyond the legacy results, e.g.
+/// `client_at_capacity`.
+pub const FEATURE_EXTENDED_OPEN_RESULTS: &str = "extended_open_results";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiatedProtocol {
+    pub version: u8,
+    pub features: Vec<String>
+}
+
+impl NegotiatedProtocol {
+
+    pub fn legacy() -> Self {
+        NegotiatedProtocol {
+            version: LEGACY_PROTOCOL_VERSION,
+            features: Vec::new()
+        }
+    }
+
+    /// Picks the lower of both versions and the features both sides offered.
+    pub(crate) fn negotiate(client_version: u8, server_version: u8, server_features: &[String]) -> Self {
+
+        let version = client_version.min(server_version);
+
+        if version == LEGACY_PROTOCOL_VERSION {
+            return Self::legacy();
+        }
+
+        let features = server_features.iter()
+            .filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect();
+
+        NegotiatedProtocol { version, features }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|negotiated_feature| negotiated_feature == feature)
+    }
+}
@@ -1,131 +1,364 @@
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use rustls::ClientConfig;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use log::warn;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
+use crate::rate_limit::{ChannelRateLimits, TokenBucket};
+use crate::protocol::global_channel_message::ProxyChannelCloseReason;
 use crate::protocol::proxy_channel_join_request::ProxyChannelJoinRequest;
 use crate::protocol::proxy_channel_join_response::ProxyChannelJoinResponse;
+use crate::secure_link_config::SecureLinkConfig;
 use crate::SecureLinkError;
 use crate::tls_connect::connect_to_domain;
 
-pub struct ProxyChannel {
+pub struct ProxyChannel<S> {
     recipient_tls_stream: TlsStream<TcpStream>,
-    sender_tcp_stream: TcpStream,
+    sender_stream: S,
+    counters: Arc<ProxyChannelCounters>,
+    rate_limits: ChannelRateLimits,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    linger_timeout: Option<Duration>
 }
 
-impl ProxyChannel {
+impl<S> ProxyChannel<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin
+{
     
     pub async fn create_proxy_channel_with_secure_link_server(secure_link_server_socket_addr: SocketAddr,
                                       secure_link_server_domain: String,
-                                      tls_config: Arc<ClientConfig>, 
-                                      sender_tcp_stream: TcpStream,
+                                      config: Arc<SecureLinkConfig>,
+                                      sender_stream: S,
                                       proxy_channel_token: String,
-    ) -> Result<ProxyChannel, SecureLinkError> {
+    ) -> Result<ProxyChannel<S>, SecureLinkError> {
         
-        let mut tls_stream =
-            connect_to_domain(
-                tls_config.clone(),
+        let join_retry_policy = &config.proxy_channel_join_retry;
+        let mut attempt: u32 = 0;
+
+        // The destination is already connected, so failures to reach the server are retried. Once
+        // the join request went out the server may have consumed the single use channel token,
+        // those failures are reported instead and the server can issue a new open request.
+        let tls_stream = loop {
+
+            let join_result = Self::join_secure_link_server(
                 secure_link_server_socket_addr,
-                secure_link_server_domain.clone()
-            )
-            .await
-            .unwrap();
-        
-        let proxy_channel_join_request = ProxyChannelJoinRequest::new(proxy_channel_token);
+                &secure_link_server_domain,
+                &config,
+                &proxy_channel_token
+            ).await;
+
+            match join_result {
+                Ok(tls_stream) => break tls_stream,
+                Err(err @ SecureLinkError::ProxyChannelConnectError(_)) if !join_retry_policy.attempts_exhausted(attempt) => {
 
-        let request_json = 
-            serde_json::to_string(&proxy_channel_join_request)
-                .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })?;
+                    let delay = join_retry_policy.delay_for_attempt(attempt);
 
-        let pdu_length = (request_json.len() as u32).to_be_bytes();
+                    warn!("proxy channel join attempt {} failed, retrying in {:?}: {}", attempt + 1, delay, err);
 
-        let mut proxy_channel_join_request_pdu= vec![0u8];
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err)
+            }
+        };
 
-        proxy_channel_join_request_pdu.extend_from_slice(&pdu_length);
-        proxy_channel_join_request_pdu.extend_from_slice(request_json.as_bytes());
+        let proxy_channel =
+            ProxyChannel {
+                recipient_tls_stream: tls_stream,
+                sender_stream,
+                counters: Arc::new(ProxyChannelCounters::new()),
+                rate_limits: ChannelRateLimits::default(),
+                idle_timeout: config.proxy_channel_idle_timeout,
+                max_lifetime: config.proxy_channel_max_lifetime,
+                linger_timeout: config.proxy_channel_linger_timeout
+            };
 
-        tls_stream.write(&proxy_channel_join_request_pdu)
-            .await
-            .map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+        Ok(proxy_channel)
 
-        let _reserved = tls_stream.read_u8()
+    }
+
+    async fn join_secure_link_server(secure_link_server_socket_addr: SocketAddr,
+                                     secure_link_server_domain: &str,
+                                     config: &SecureLinkConfig,
+                                     proxy_channel_token: &str
+    ) -> Result<TlsStream<TcpStream>, SecureLinkError> {
+
+        let (mut tls_stream, _) =
+            connect_to_domain(
+                config.tls_config.clone(),
+                &[secure_link_server_socket_addr],
+                secure_link_server_domain.to_string(),
+                config.connect_timeout,
+                config.tls_handshake_timeout
+            )
             .await
-            .map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
-        
-        let length = 
-            tls_stream.read_u32()
-                .await
-                .map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+            .map_err(|err| { SecureLinkError::ProxyChannelConnectError(err.into()) })?;
 
-        let mut message = vec![0; length as usize];
+        let proxy_channel_join_request = ProxyChannelJoinRequest::new(proxy_channel_token.to_string());
 
-        tls_stream.read_exact(&mut message).
-            await
-            .map_err(|err| { SecureLinkError::TlsStreamError(Box::new(err)) })?;
+        config.frame_codec.write_frame(&mut tls_stream, &proxy_channel_join_request).await?;
 
-        let channel_join_response = 
-            serde_json::from_slice::<ProxyChannelJoinResponse>(&message)
-                .map_err(|err| { SecureLinkError::ProtocolSerializationError(Box::new(err)) })?;
-        
-        match channel_join_response {
-            ProxyChannelJoinResponse::ProxyChannelJoinConfirmed(_) => {
-                
-                let proxy_channel =
-                    ProxyChannel {
-                        recipient_tls_stream: tls_stream,
-                        sender_tcp_stream
-                    };
+        let channel_join_response: ProxyChannelJoinResponse =
+            config.frame_codec.read_frame_within_deadline(&mut tls_stream).await?;
 
-                Ok(proxy_channel)
-                
-            }
-            ProxyChannelJoinResponse::ProxyChannelJoinDenied(_) => {
-                
-                Err(SecureLinkError::ProxyChannelJoinDenied)
-                
-            }
+        match channel_join_response {
+            ProxyChannelJoinResponse::ProxyChannelJoinConfirmed(_) => Ok(tls_stream),
+            ProxyChannelJoinResponse::ProxyChannelJoinDenied(_) => Err(SecureLinkError::ProxyChannelJoinDenied)
         }
 
     }
     
-    pub async fn run_proxy_between_sender_and_secure_link_server(self) -> Result<(), SecureLinkError> {
+    pub fn counters(&self) -> Arc<ProxyChannelCounters> {
+        self.counters.clone()
+    }
+
+    pub(crate) fn with_rate_limits(mut self, rate_limits: ChannelRateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    /// Returns why the channel ended. Resets and truncated TLS streams are close reasons, other
+    /// I/O errors are `Err`.
+    pub async fn run_proxy_between_sender_and_secure_link_server(self) -> Result<ProxyChannelCloseReason, SecureLinkError> {
 
-        let sender_tcp_stream = self.sender_tcp_stream;
+        let sender_stream = self.sender_stream;
         let recipient_tls_stream = self.recipient_tls_stream;
-        
+        let counters = self.counters;
+        let rate_limits = self.rate_limits;
+        let idle_timeout = self.idle_timeout;
+        let max_lifetime = self.max_lifetime;
+        let linger_timeout = self.linger_timeout;
+
         // Split the TLS stream into its read and write halves
         let (mut recipient_tls_read, mut recipient_tls_write) = tokio::io::split(recipient_tls_stream);
 
-        // Split the TCP stream into its read and write halves
-        let (mut sender_tcp_read, mut sender_tcp_write) = tokio::io::split(sender_tcp_stream);
-
-        // Copy sender -> recipient
-        // Copy sender -> recipient
-        let sender_to_recipient = async {
-            
-            let result = tokio::io::copy(&mut sender_tcp_read, &mut recipient_tls_write).await
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>);
-            
-            let _ = recipient_tls_write.shutdown().await; // Ignore shutdown errors
-            
-            result.map(|_| ())
+        // Split the destination stream into its read and write halves
+        let (mut sender_read, mut sender_write) = tokio::io::split(sender_stream);
+
+        let outcome = {
+
+            // Copy sender -> recipient, then half-close towards the secure link server
+            // (close_notify followed by FIN) while the other direction keeps running
+            let sender_to_recipient = async {
+                let result = copy_counted(&mut sender_read, &mut recipient_tls_write, &counters, &counters.bytes_uploaded, &rate_limits.upload).await;
+                let _ = recipient_tls_write.shutdown().await; // Ignore shutdown errors
+                result
+            };
+
+            // Copy recipient -> sender, then half-close towards the destination
+            let recipient_to_sender = async {
+                let result = copy_counted(&mut recipient_tls_read, &mut sender_write, &counters, &counters.bytes_downloaded, &rate_limits.download).await;
+                let _ = sender_write.shutdown().await; // Ignore shutdown errors
+                result
+            };
+
+            tokio::pin!(sender_to_recipient, recipient_to_sender);
+
+            let mut sender_to_recipient_done = false;
+            let mut recipient_to_sender_done = false;
+            let mut half_closed_at: Option<Instant> = None;
+            let mut first_error: Option<std::io::Error> = None;
+
+            loop {
+
+                if sender_to_recipient_done && recipient_to_sender_done {
+                    break match first_error {
+                        Some(err) => Err(err),
+                        None => Ok(ProxyChannelCloseReason::Completed)
+                    };
+                }
+
+                let direction_result = tokio::select! {
+
+                    result = &mut sender_to_recipient, if !sender_to_recipient_done => {
+                        sender_to_recipient_done = true;
+                        result
+                    }
+
+                    result = &mut recipient_to_sender, if !recipient_to_sender_done => {
+                        recipient_to_sender_done = true;
+                        result
+                    }
+
+                    reason = wait_for_expiry(&counters, idle_timeout, max_lifetime) => break Ok(reason),
+
+                    _ = wait_for_linger(&counters, half_closed_at.unwrap_or_else(Instant::now), linger_timeout), if half_closed_at.is_some() => {
+                        break Ok(ProxyChannelCloseReason::LingerTimeout)
+                    }
+
+                };
+
+                half_closed_at.get_or_insert_with(Instant::now);
+
+                if let Err(err) = direction_result {
+                    first_error.get_or_insert(err);
+                }
+            }
         };
 
-        // Copy recipient -> sender  
-        let recipient_to_sender = async {
-            let result = tokio::io::copy(&mut recipient_tls_read, &mut sender_tcp_write).await
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>);
-            let _ = sender_tcp_write.shutdown().await; // Ignore shutdown errors
-            result.map(|_| ())
+        match outcome {
+            Ok(ProxyChannelCloseReason::Completed) => Ok(ProxyChannelCloseReason::Completed),
+            Ok(reason) => {
+
+                // Close both directions so neither peer keeps waiting for more data
+                let _ = recipient_tls_write.shutdown().await;
+                let _ = sender_write.shutdown().await;
+
+                Ok(reason)
+            }
+            Err(err) if is_connection_reset(&err) => {
+                warn!("proxy channel reset: {}", err);
+                Ok(ProxyChannelCloseReason::Reset)
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                // A TLS peer closed the TCP connection without close_notify
+                warn!("proxy channel truncated: {}", err);
+                Ok(ProxyChannelCloseReason::Truncated)
+            }
+            Err(err) => Err(SecureLinkError::TlsStreamError(Box::new(err)))
+        }
+
+    }
+
+}
+
+pub struct ProxyChannelCounters {
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    started_at: Instant,
+    /// Milliseconds after `started_at` at which data last moved in either direction.
+    last_activity_millis: AtomicU64
+}
+
+impl ProxyChannelCounters {
+
+    fn new() -> Self {
+        ProxyChannelCounters {
+            bytes_uploaded: AtomicU64::new(0),
+            bytes_downloaded: AtomicU64::new(0),
+            started_at: Instant::now(),
+            last_activity_millis: AtomicU64::new(0)
+        }
+    }
+
+    fn record_activity(&self) {
+        self.last_activity_millis.store(self.started_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last_activity(&self) -> Instant {
+        self.started_at + Duration::from_millis(self.last_activity_millis.load(Ordering::Relaxed))
+    }
+
+    pub fn bytes_uploaded(&self) -> u64 {
+        self.bytes_uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.bytes_downloaded.load(Ordering::Relaxed)
+    }
+}
+
+const COPY_BUFFER_SIZE: usize = 8 * 1024;
+
+async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    counters: &ProxyChannelCounters,
+    transferred: &AtomicU64,
+    rate_limits: &[Arc<TokenBucket>]
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin
+{
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+
+    loop {
+
+        let read = reader.read(&mut buffer).await?;
+
+        if read == 0 {
+            writer.flush().await?;
+            return Ok(());
+        }
+
+        for token_bucket in rate_limits {
+            token_bucket.acquire(read).await;
+        }
+
+        // tokio-rustls may keep ciphertext buffered after accepting the plaintext, without a flush
+        // the last bytes of an exchange could wait there until the peer sends again
+        writer.write_all(&buffer[..read]).await?;
+        writer.flush().await?;
+        transferred.fetch_add(read as u64, Ordering::Relaxed);
+        counters.record_activity();
+    }
+}
+
+/// Resolves once the channel was idle for `idle_timeout` or lived for `max_lifetime`,
+/// never when neither is set.
+async fn wait_for_expiry(
+    counters: &ProxyChannelCounters,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>
+) -> ProxyChannelCloseReason {
+
+    let lifetime_deadline = max_lifetime.map(|max_lifetime| counters.started_at + max_lifetime);
+
+    loop {
+
+        let idle_deadline = idle_timeout.map(|idle_timeout| counters.last_activity() + idle_timeout);
+
+        let next_deadline = match (idle_deadline, lifetime_deadline) {
+            (Some(idle_deadline), Some(lifetime_deadline)) => idle_deadline.min(lifetime_deadline),
+            (Some(deadline), None) | (None, Some(deadline)) => deadline,
+            (None, None) => return std::future::pending().await
         };
 
-        // Run both tasks concurrently
-        tokio::try_join!(sender_to_recipient, recipient_to_sender)
-            .map_err(|err| { SecureLinkError::TlsStreamError(err) })?;
-        
-        Ok(())
-        
+        tokio::time::sleep_until(next_deadline.into()).await;
+
+        let now = Instant::now();
+
+        if lifetime_deadline.is_some_and(|lifetime_deadline| now >= lifetime_deadline) {
+            return ProxyChannelCloseReason::MaxLifetimeExceeded;
+        }
+
+        // Data may have moved while sleeping, in that case the idle deadline moved as well
+        if idle_timeout.is_some_and(|idle_timeout| now >= counters.last_activity() + idle_timeout) {
+            return ProxyChannelCloseReason::IdleTimeout;
+        }
     }
-    
-}
\ No newline at end of file
+}
+
+/// Resolves once nothing moved for `linger_timeout` after one direction finished, so a
+/// half-closed channel whose remaining direction went quiet does not stay open forever.
+/// Never resolves without a linger timeout.
+async fn wait_for_linger(counters: &ProxyChannelCounters, half_closed_at: Instant, linger_timeout: Option<Duration>) {
+
+    let Some(linger_timeout) = linger_timeout else {
+        return std::future::pending().await;
+    };
+
+    loop {
+
+        let linger_deadline = counters.last_activity().max(half_closed_at) + linger_timeout;
+
+        if Instant::now() >= linger_deadline {
+            return;
+        }
+
+        tokio::time::sleep_until(linger_deadline.into()).await;
+    }
+}
+
+fn is_connection_reset(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    )
+}
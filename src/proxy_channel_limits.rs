@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::protocol::global_channel_message::ProxyDestination;
+
+/// Caps on concurrent proxy channels, `None` leaves a dimension unbounded. Channels that are
+/// still connecting or joining count towards every cap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxyChannelLimits {
+    pub max_active_channels: Option<usize>,
+    pub max_opening_channels: Option<usize>,
+    pub max_channels_per_destination: Option<usize>
+}
+
+pub(crate) struct ProxyChannelLimiter {
+    limits: ProxyChannelLimits,
+    usage: Mutex<ProxyChannelUsage>
+}
+
+#[derive(Default)]
+struct ProxyChannelUsage {
+    active_channels: usize,
+    opening_channels: usize,
+    channels_per_destination: HashMap<ProxyDestination, usize>
+}
+
+/// Held by a proxy channel task for its whole life, releases its slots when dropped.
+pub(crate) struct ProxyChannelPermit {
+    limiter: Arc<ProxyChannelLimiter>,
+    destination: ProxyDestination,
+    opening: bool
+}
+
+impl ProxyChannelLimiter {
+
+    pub fn new(limits: ProxyChannelLimits) -> Self {
+        ProxyChannelLimiter {
+            limits,
+            usage: Mutex::new(ProxyChannelUsage::default())
+        }
+    }
+
+    /// Returns `None` when any cap is already reached.
+    pub fn try_acquire(self: &Arc<Self>, destination: &ProxyDestination) -> Option<ProxyChannelPermit> {
+
+        let mut usage = self.usage.lock().unwrap();
+
+        let channels_to_destination = usage.channels_per_destination.get(destination).copied().unwrap_or(0);
+
+        let at_capacity =
+            self.limits.max_active_channels.is_some_and(|max| usage.active_channels >= max)
+                || self.limits.max_opening_channels.is_some_and(|max| usage.opening_channels >= max)
+                || self.limits.max_channels_per_destination.is_some_and(|max| channels_to_destination >= max);
+
+        if at_capacity {
+            return None;
+        }
+
+        usage.active_channels += 1;
+        usage.opening_channels += 1;
+        *usage.channels_per_destination.entry(destination.clone()).or_default() += 1;
+
+        Some(ProxyChannelPermit {
+            limiter: self.clone(),
+            destination: destination.clone(),
+            opening: true
+        })
+    }
+}
+
+impl ProxyChannelPermit {
+
+    /// The channel joined the secure link server and no longer counts as opening.
+    pub fn opened(&mut self) {
+        if self.opening {
+            self.opening = false;
+            self.limiter.usage.lock().unwrap().opening_channels -= 1;
+        }
+    }
+}
+
+impl Drop for ProxyChannelPermit {
+    fn drop(&mut self) {
+
+        let mut usage = self.limiter.usage.lock().unwrap();
+
+        usage.active_channels -= 1;
+
+        if self.opening {
+            usage.opening_channels -= 1;
+        }
+
+        if let Some(channels_to_destination) = usage.channels_per_destination.get_mut(&self.destination) {
+            *channels_to_destination -= 1;
+
+            if *channels_to_destination == 0 {
+                usage.channels_per_destination.remove(&self.destination);
+            }
+        }
+    }
+}
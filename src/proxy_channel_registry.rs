@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use futures::future::AbortHandle;
+use tokio::sync::{oneshot, Notify};
+use crate::protocol::global_channel_message::{ProxyChannelCloseReason, ProxyDestination};
+use crate::proxy_channel::ProxyChannelCounters;
+use crate::traffic_stats::ProxyChannelRecord;
+
+#[derive(Debug, Clone)]
+pub struct ProxyChannelInfo {
+    pub proxy_channel_id: String,
+    pub secure_link_session_id: String,
+    pub destination: ProxyDestination,
+    pub state: ProxyChannelState,
+    /// When the open request was accepted.
+    pub started_at: SystemTime,
+    pub duration: Duration,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyChannelState {
+    /// Connecting to the destination.
+    Connecting,
+    /// Joining the secure link server.
+    Joining,
+    /// Forwarding data.
+    Active
+}
+
+/// Proxy channels from the open request until they end, keyed by `proxy_channel_id`.
+/// Shared across reconnects since channels outlive their global channel.
+#[derive(Default)]
+pub(crate) struct ProxyChannelRegistry {
+    channels: Mutex<HashMap<String, ActiveProxyChannel>>,
+    unregistered: Notify
+}
+
+struct ActiveProxyChannel {
+    secure_link_session_id: String,
+    destination: ProxyDestination,
+    state: ProxyChannelState,
+    started_at: SystemTime,
+    started_at_instant: Instant,
+    /// Set once the channel is active.
+    counters: Option<Arc<ProxyChannelCounters>>,
+    close_sender: Option<oneshot::Sender<ProxyChannelCloseReason>>,
+    abort_handle: AbortHandle
+}
+
+/// Removes the channel from the registry when its task ends, however it ends.
+pub(crate) struct ProxyChannelRegistration {
+    registry: Arc<ProxyChannelRegistry>,
+    proxy_channel_id: String,
+    secure_link_session_id: String,
+    destination: ProxyDestination,
+    started_at: Instant
+}
+
+impl ProxyChannelRegistry {
+
+    pub fn register(
+        self: &Arc<Self>,
+        proxy_channel_id: &str,
+        secure_link_session_id: &str,
+        destination: ProxyDestination,
+        abort_handle: AbortHandle
+    ) -> Option<(ProxyChannelRegistration, oneshot::Receiver<ProxyChannelCloseReason>)> {
+
+        let mut channels = self.channels.lock().unwrap();
+
+        // The running channel keeps the id, the server must not reuse it before the close
+        if channels.contains_key(proxy_channel_id) {
+            return None;
+        }
+
+        let (close_sender, close_receiver) = oneshot::channel();
+        let started_at = Instant::now();
+
+        channels.insert(
+            proxy_channel_id.to_string(),
+            ActiveProxyChannel {
+                secure_link_session_id: secure_link_session_id.to_string(),
+                destination: destination.clone(),
+                state: ProxyChannelState::Connecting,
+                started_at: SystemTime::now(),
+                started_at_instant: started_at,
+                counters: None,
+                close_sender: Some(close_sender),
+                abort_handle
+            }
+        );
+
+        let proxy_channel_registration = ProxyChannelRegistration {
+            registry: self.clone(),
+            proxy_channel_id: proxy_channel_id.to_string(),
+            secure_link_session_id: secure_link_session_id.to_string(),
+            destination,
+            started_at
+        };
+
+        Some((proxy_channel_registration, close_receiver))
+    }
+
+    /// Asks a channel to stop in whatever state it is, returns false if no such channel exists.
+    pub fn close(&self, proxy_channel_id: &str, reason: ProxyChannelCloseReason) -> bool {
+
+        let close_sender = self.channels.lock().unwrap()
+            .get_mut(proxy_channel_id)
+            .and_then(|active_proxy_channel| active_proxy_channel.close_sender.take());
+
+        match close_sender {
+            Some(close_sender) => close_sender.send(reason).is_ok(),
+            None => false
+        }
+    }
+
+    /// Asks every channel to stop, returns how many were asked.
+    pub fn close_all(&self, reason: ProxyChannelCloseReason) -> usize {
+
+        let close_senders: Vec<_> = self.channels.lock().unwrap()
+            .values_mut()
+            .filter_map(|active_proxy_channel| active_proxy_channel.close_sender.take())
+            .collect();
+
+        close_senders
+            .into_iter()
+            .map(|close_sender| close_sender.send(reason))
+            .filter(Result::is_ok)
+            .count()
+    }
+
+    /// Stops every channel task right away, without notifying the server. Returns how many
+    /// channels were still registered.
+    pub fn abort_all(&self) -> usize {
+
+        let channels = self.channels.lock().unwrap();
+
+        for active_proxy_channel in channels.values() {
+            active_proxy_channel.abort_handle.abort();
+        }
+
+        channels.len()
+    }
+
+    pub async fn wait_until_empty(&self) {
+        loop {
+
+            let unregistered = self.unregistered.notified();
+            tokio::pin!(unregistered);
+
+            // Registers interest before checking, so a channel ending in between is not missed
+            unregistered.as_mut().enable();
+
+            if self.channels.lock().unwrap().is_empty() {
+                return;
+            }
+
+            unregistered.await;
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<ProxyChannelInfo> {
+        self.channels.lock().unwrap()
+            .iter()
+            .map(|(proxy_channel_id, active_proxy_channel)| ProxyChannelInfo {
+                proxy_channel_id: proxy_channel_id.clone(),
+                secure_link_session_id: active_proxy_channel.secure_link_session_id.clone(),
+                destination: active_proxy_channel.destination.clone(),
+                state: active_proxy_channel.state,
+                started_at: active_proxy_channel.started_at,
+                duration: active_proxy_channel.started_at_instant.elapsed(),
+                bytes_uploaded: active_proxy_channel.counters.as_ref().map_or(0, |counters| counters.bytes_uploaded()),
+                bytes_downloaded: active_proxy_channel.counters.as_ref().map_or(0, |counters| counters.bytes_downloaded())
+            })
+            .collect()
+    }
+}
+
+impl ProxyChannelRegistration {
+
+    /// Time since the open request was accepted, the same start `ProxyChannelInfo` uses.
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Unregisters the channel and describes how it ended, also for channels that never joined.
+    pub fn into_record(self, bytes_uploaded: u64, bytes_downloaded: u64, reason: ProxyChannelCloseReason) -> ProxyChannelRecord {
+        ProxyChannelRecord {
+            proxy_channel_id: self.proxy_channel_id.clone(),
+            secure_link_session_id: self.secure_link_session_id.clone(),
+            destination: self.destination.clone(),
+            bytes_uploaded,
+            bytes_downloaded,
+            duration: self.elapsed(),
+            reason
+        }
+    }
+
+    pub fn joining(&self) {
+        self.update(|active_proxy_channel| active_proxy_channel.state = ProxyChannelState::Joining);
+    }
+
+    pub fn active(&self, counters: Arc<ProxyChannelCounters>) {
+        self.update(|active_proxy_channel| {
+            active_proxy_channel.state = ProxyChannelState::Active;
+            active_proxy_channel.counters = Some(counters);
+        });
+    }
+
+    fn update(&self, update: impl FnOnce(&mut ActiveProxyChannel)) {
+
+        let mut channels = self.registry.channels.lock().unwrap();
+
+        if let Some(active_proxy_channel) = channels.get_mut(&self.proxy_channel_id) {
+            update(active_proxy_channel);
+        }
+    }
+}
+
+impl Drop for ProxyChannelRegistration {
+    fn drop(&mut self) {
+
+        let mut channels = self.registry.channels.lock().unwrap();
+
+        // Ids are unique while registered, so the entry is always this channel's own
+        if channels.remove(&self.proxy_channel_id).is_some() {
+            self.registry.unregistered.notify_waiters();
+        }
+    }
+}
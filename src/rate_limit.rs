@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::protocol::global_channel_message::ProxyDestination;
+
+/// Upload and download caps in bytes per second, `None` leaves a direction unthrottled.
+/// Upload is traffic from the destination towards the secure link server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    pub upload_bytes_per_second: Option<u64>,
+    pub download_bytes_per_second: Option<u64>
+}
+
+impl BandwidthLimit {
+
+    pub fn upload(mut self, bytes_per_second: u64) -> Self {
+        self.upload_bytes_per_second = Some(bytes_per_second);
+        self
+    }
+
+    pub fn download(mut self, bytes_per_second: u64) -> Self {
+        self.download_bytes_per_second = Some(bytes_per_second);
+        self
+    }
+}
+
+/// Allows bursts of up to one second worth of traffic. A chunk larger than the available
+/// tokens is let through and the bucket goes into debt, the next caller waits it off.
+pub(crate) struct TokenBucket {
+    bytes_per_second: u64,
+    state: Mutex<TokenBucketState>
+}
+
+struct TokenBucketState {
+    tokens: f64,
+    refilled_at: Instant
+}
+
+impl TokenBucket {
+
+    fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second.max(1);
+
+        TokenBucket {
+            bytes_per_second,
+            state: Mutex::new(TokenBucketState {
+                tokens: bytes_per_second as f64,
+                refilled_at: Instant::now()
+            })
+        }
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+
+            let now = Instant::now();
+            let refill = now.duration_since(state.refilled_at).as_secs_f64() * self.bytes_per_second as f64;
+
+            state.tokens = (state.tokens + refill).min(self.bytes_per_second as f64);
+            state.refilled_at = now;
+            state.tokens -= bytes as f64;
+
+            match state.tokens < 0.0 {
+                true => Some(Duration::from_secs_f64(-state.tokens / self.bytes_per_second as f64)),
+                false => None
+            }
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The buckets a single proxy channel draws from, in both directions.
+#[derive(Default)]
+pub(crate) struct ChannelRateLimits {
+    pub upload: Vec<Arc<TokenBucket>>,
+    pub download: Vec<Arc<TokenBucket>>
+}
+
+impl ChannelRateLimits {
+
+    fn push(&mut self, buckets: &DirectionalBuckets) {
+        self.upload.extend(buckets.upload.clone());
+        self.download.extend(buckets.download.clone());
+    }
+}
+
+#[derive(Default)]
+struct DirectionalBuckets {
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>
+}
+
+impl DirectionalBuckets {
+
+    fn new(bandwidth_limit: BandwidthLimit) -> Self {
+        DirectionalBuckets {
+            upload: bandwidth_limit.upload_bytes_per_second.map(|rate| Arc::new(TokenBucket::new(rate))),
+            download: bandwidth_limit.download_bytes_per_second.map(|rate| Arc::new(TokenBucket::new(rate)))
+        }
+    }
+}
+
+pub(crate) struct RateLimiters {
+    global: DirectionalBuckets,
+    per_destination_limit: BandwidthLimit,
+    per_destination: Mutex<HashMap<ProxyDestination, DirectionalBuckets>>,
+    per_channel_limit: BandwidthLimit
+}
+
+impl RateLimiters {
+
+    pub fn new(global_limit: BandwidthLimit, per_destination_limit: BandwidthLimit, per_channel_limit: BandwidthLimit) -> Self {
+        RateLimiters {
+            global: DirectionalBuckets::new(global_limit),
+            per_destination_limit,
+            per_destination: Mutex::new(HashMap::new()),
+            per_channel_limit
+        }
+    }
+
+    pub fn for_channel(&self, destination: &ProxyDestination) -> ChannelRateLimits {
+
+        let mut channel_rate_limits = ChannelRateLimits::default();
+
+        channel_rate_limits.push(&self.global);
+
+        if self.per_destination_limit != BandwidthLimit::default() {
+
+            let mut per_destination = self.per_destination.lock().unwrap();
+
+            // Buckets only referenced by the map belong to destinations without active channels
+            per_destination.retain(|_, buckets| {
+                buckets.upload.as_ref().is_some_and(|bucket| Arc::strong_count(bucket) > 1)
+                    || buckets.download.as_ref().is_some_and(|bucket| Arc::strong_count(bucket) > 1)
+            });
+
+            let destination_buckets = per_destination
+                .entry(destination.clone())
+                .or_insert_with(|| DirectionalBuckets::new(self.per_destination_limit));
+
+            channel_rate_limits.push(destination_buckets);
+        }
+
+        channel_rate_limits.push(&DirectionalBuckets::new(self.per_channel_limit));
+
+        channel_rate_limits
+    }
+}
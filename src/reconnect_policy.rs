@@ -0,0 +1,57 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Fraction of the computed delay that is randomized, `0.0..=1.0`.
+    pub jitter: f64,
+    /// `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+
+    pub(crate) fn attempts_exhausted(&self, attempt: u32) -> bool {
+        match self.max_attempts {
+            Some(max_attempts) => attempt >= max_attempts,
+            None => false
+        }
+    }
+
+    pub(crate) fn delay_for_attempt(&self, attempt: u32) -> Duration {
+
+        let exponent = attempt.min(32) as i32;
+
+        let base_delay_secs =
+            (self.initial_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exponent))
+                .min(self.max_delay.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+
+        // full delay minus a random share of up to `jitter` of it
+        let delay_secs = base_delay_secs * (1.0 - jitter * random_unit());
+
+        Duration::from_secs_f64(delay_secs.max(0.0))
+    }
+}
+
+pub(crate) fn random_unit() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -1,63 +1,343 @@
-use std::net::ToSocketAddrs;
+use std::future::Future;
 use std::sync::Arc;
-use rustls::{ClientConfig, RootCertStore};
+use std::time::Duration;
+use log::{error, info, warn};
+use tokio::sync::broadcast;
+use crate::auth_token_provider::DynAuthTokenProvider;
+use crate::dev_cert_report::DevCertificateReport;
 use crate::global_channel::GlobalChannel;
+use crate::health_check::HealthCheckStats;
+use crate::protocol::global_channel_message::ProxyChannelCloseReason;
+use crate::proxy_channel_registry::ProxyChannelInfo;
+use crate::traffic_stats::TrafficStats;
+use crate::reconnect_policy::ReconnectPolicy;
+use crate::secure_link_builder::SecureLinkBuilder;
+use crate::secure_link_config::SecureLinkConfig;
+use crate::secure_link_endpoint::{SecureLinkEndpoint, SecureLinkEndpoints};
+use crate::secure_link_event::SecureLinkEvent;
+use crate::shutdown::ShutdownHandle;
+use crate::tls_connect::resolve_domain;
 use crate::SecureLinkError;
 
+/// How long proxy channels closed at the drain timeout get to report before they are aborted.
+const SHUTDOWN_CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
 pub struct SecureLink {
-    global_channel: Option<GlobalChannel>
+    endpoints: SecureLinkEndpoints,
+    config: Arc<SecureLinkConfig>,
+    auth_token_provider: Arc<dyn DynAuthTokenProvider>,
+    global_channel: Option<GlobalChannel>,
+    shutdown_handle: ShutdownHandle
 }
 
 impl SecureLink {
-    
+
+    pub fn builder(
+        secure_link_server_host: &str,
+        secure_link_server_port: u16,
+        auth_token: &str,
+    ) -> SecureLinkBuilder {
+        SecureLinkBuilder::new(secure_link_server_host, secure_link_server_port, auth_token)
+    }
+
     pub async fn connect_to_global_channel(
-        secure_link_server_host: &str, 
+        secure_link_server_host: &str,
         secure_link_server_port: u16,
         auth_token: &str,
     ) -> Result<SecureLink, SecureLinkError> {
+        Self::builder(secure_link_server_host, secure_link_server_port, auth_token)
+            .connect()
+            .await
+    }
 
-        let mut root_cert_store = RootCertStore::empty();
-        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    /// With a reconnect policy, retryable failures of the first connect are retried the same
+    /// way a lost global channel is.
+    pub(crate) async fn connect_with_config(
+        endpoints: SecureLinkEndpoints,
+        auth_token_provider: Arc<dyn DynAuthTokenProvider>,
+        config: Arc<SecureLinkConfig>,
+        reconnect_policy: Option<&ReconnectPolicy>
+    ) -> Result<SecureLink, SecureLinkError> {
 
-        #[cfg(feature = "load_dev_certs")]
-        crate::dev_cert_loader::DevCertLoader::load_dev_certs(&mut root_cert_store).await.unwrap();
-        
-        let config = ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth();
+        let mut secure_link = SecureLink {
+            endpoints,
+            config,
+            auth_token_provider,
+            global_channel: None,
+            shutdown_handle: ShutdownHandle::new()
+        };
 
-        let tls_config = Arc::new(config);
+        let connect_result =
+            connect_to_any_endpoint(&secure_link.endpoints, &secure_link.config, secure_link.auth_token_provider.as_ref()).await;
 
-        let socket_addr = match (secure_link_server_host, secure_link_server_port).to_socket_addrs() {
-            Ok(mut addrs) => match addrs.next() {
-                Some(addr) => addr,
-                None => {
-                    log::error!("Unable to resolve server address");
-                    return Err(SecureLinkError::BadHostError);
-                }
-            },
-            Err(e) => {
-                log::error!("Invalid server address: {}", e);
-                return Err(SecureLinkError::BadHostError);
+        let global_channel = match (connect_result, reconnect_policy) {
+            (Ok(global_channel), _) => global_channel,
+            (Err(err), Some(reconnect_policy)) if err.is_retryable() => {
+                warn!("unable to connect to secure link server: {}, retrying", err);
+                secure_link.reconnect_global_channel(reconnect_policy, err).await?
             }
+            (Err(err), _) => return Err(err)
         };
-        
-        let global_channel = 
-            GlobalChannel::create_global_channel(
-                socket_addr,
-                secure_link_server_host.to_string(),
-                tls_config,
-                auth_token.to_string()
-            ).await?;
-        
-        
-        Ok(SecureLink { global_channel: Some(global_channel) })
-        
-    }
-    
-    pub async fn run_message_loop(self) -> Result<(), SecureLinkError> {
-        self.global_channel.unwrap().run_message_loop().await?;
+
+        secure_link.global_channel = Some(global_channel);
+
+        Ok(secure_link)
+
+    }
+
+    /// The endpoint the global channel is connected to, or was connected to last.
+    pub fn current_endpoint(&self) -> Option<SecureLinkEndpoint> {
+        self.endpoints.healthy_endpoint()
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SecureLinkEvent> {
+        self.config.events.subscribe()
+    }
+
+    /// Round trip times of the client's own health checks, kept across reconnects.
+    pub fn health_check_stats(&self) -> Option<HealthCheckStats> {
+        self.config.health_check_rtt.stats()
+    }
+
+    pub fn active_proxy_channels(&self) -> Vec<ProxyChannelInfo> {
+        self.config.proxy_channel_registry.snapshot()
+    }
+
+    /// Traffic of every proxy channel since the client was built, for billing and capacity planning.
+    pub fn traffic_stats(&self) -> TrafficStats {
+        self.config.traffic_accounting.snapshot(self.config.proxy_channel_registry.snapshot())
+    }
+
+    /// Which dev certificates were trusted, `None` unless built with `load_dev_certs` and the
+    /// default TLS config.
+    pub fn dev_certificate_report(&self) -> Option<&DevCertificateReport> {
+        self.config.dev_certificate_report.as_ref()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    pub async fn run_message_loop(mut self) -> Result<(), SecureLinkError> {
+
+        let global_channel = self.global_channel.take().unwrap();
+        let shutdown_signal = self.shutdown_handle.signal();
+
+        self.run_until_drained(global_channel.run_message_loop(shutdown_signal)).await
+
+    }
+
+    /// Runs the message loop and re-creates the global channel whenever it drops with a
+    /// retryable error. Proxy channels run on their own connections and are not interrupted.
+    pub async fn run_message_loop_with_reconnect(mut self, reconnect_policy: ReconnectPolicy) -> Result<(), SecureLinkError> {
+
+        let global_channel = self.global_channel.take().unwrap();
+
+        self.run_until_drained(self.supervise_global_channel(global_channel, &reconnect_policy)).await
+
+    }
+
+    /// Drains the proxy channels on shutdown, whether the global channel is still up, dropped
+    /// during the drain or was being reconnected when shutdown was requested.
+    async fn run_until_drained(&self, message_loop: impl Future<Output = Result<(), SecureLinkError>>) -> Result<(), SecureLinkError> {
+
+        let drain = drain_proxy_channels(&self.config, &self.shutdown_handle);
+        tokio::pin!(drain);
+        tokio::pin!(message_loop);
+
+        let mut drained = false;
+
+        // A connected global channel leaves once the drain finished
+        let result = tokio::select! {
+            result = &mut message_loop => result,
+            () = &mut drain => {
+                drained = true;
+                message_loop.await
+            }
+        };
+
+        if !self.shutdown_handle.is_shutdown_requested() {
+            return result;
+        }
+
+        if !drained {
+            drain.await;
+        }
+
         Ok(())
+
+    }
+
+    async fn supervise_global_channel(&self, mut global_channel: GlobalChannel, reconnect_policy: &ReconnectPolicy) -> Result<(), SecureLinkError> {
+
+        loop {
+
+            match global_channel.run_message_loop(self.shutdown_handle.signal()).await {
+                Ok(()) => return Ok(()),
+                Err(_) if self.shutdown_handle.is_shutdown_requested() => return Ok(()),
+                Err(err) if err.is_retryable() => {
+                    warn!("global channel lost: {}, reconnecting", err);
+                    global_channel = match self.reconnect_global_channel(reconnect_policy, err).await {
+                        Ok(global_channel) => global_channel,
+                        Err(_) if self.shutdown_handle.is_shutdown_requested() => return Ok(()),
+                        Err(err) => return Err(err)
+                    };
+                }
+                Err(err) => {
+                    error!("global channel failed with non retryable error: {}", err);
+                    return Err(err);
+                }
+            }
+
+        }
+
     }
-    
-}
\ No newline at end of file
+
+    async fn reconnect_global_channel(&self, reconnect_policy: &ReconnectPolicy, cause: SecureLinkError) -> Result<GlobalChannel, SecureLinkError> {
+
+        let mut last_error = cause;
+        let mut attempt: u32 = 0;
+
+        loop {
+
+            if reconnect_policy.attempts_exhausted(attempt) {
+                error!("giving up reconnecting after {} attempts: {}", attempt, last_error);
+                return Err(last_error);
+            }
+
+            let delay = reconnect_policy.delay_for_attempt(attempt);
+
+            info!("reconnecting to secure link server in {:?} (attempt {})", delay, attempt + 1);
+
+            let shutdown_signal = self.shutdown_handle.signal();
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown_signal.requested() => {
+                    info!("shutdown requested while reconnecting");
+                    return Err(last_error);
+                }
+            }
+
+            attempt += 1;
+
+            let create_global_channel_result =
+                connect_to_any_endpoint(&self.endpoints, &self.config, self.auth_token_provider.as_ref()).await;
+
+            match create_global_channel_result {
+                Ok(global_channel) => {
+                    info!("reconnected to secure link server");
+                    return Ok(global_channel);
+                }
+                Err(err) if err.is_retryable() => {
+                    warn!("reconnect attempt {} failed: {}", attempt, err);
+                    last_error = err;
+                }
+                Err(err) => {
+                    error!("reconnect attempt {} failed with non retryable error: {}", attempt, err);
+                    return Err(err);
+                }
+            }
+
+        }
+
+    }
+
+}
+
+/// Waits for a shutdown request, then gives the proxy channels the drain timeout to finish.
+/// Channels still running after it are closed with `ShutdownTimeout`, so they are recorded and
+/// reported like any other close, and aborted if they do not stop within a grace period.
+async fn drain_proxy_channels(config: &SecureLinkConfig, shutdown_handle: &ShutdownHandle) {
+
+    let drain_timeout = shutdown_handle.signal().requested().await;
+
+    info!("shutdown requested, draining proxy channels for up to {:?}", drain_timeout);
+
+    // Includes the proxy channels opened on global channels before a reconnect
+    let registry = &config.proxy_channel_registry;
+
+    if tokio::time::timeout(drain_timeout, registry.wait_until_empty()).await.is_ok() {
+        info!("all proxy channels finished");
+    } else {
+
+        let closed = registry.close_all(ProxyChannelCloseReason::ShutdownTimeout);
+        warn!("drain timeout reached, closing {} remaining proxy channels", closed);
+
+        if tokio::time::timeout(SHUTDOWN_CLOSE_GRACE_PERIOD, registry.wait_until_empty()).await.is_err() {
+            let aborted = registry.abort_all();
+            warn!("aborted {} proxy channels that did not close in time", aborted);
+        }
+    }
+
+    shutdown_handle.drained();
+
+}
+
+/// Tries the endpoints in order until one accepts the join. Non retryable errors such as a
+/// denied join are preferred over connection errors when every endpoint failed.
+async fn connect_to_any_endpoint(
+    endpoints: &SecureLinkEndpoints,
+    config: &Arc<SecureLinkConfig>,
+    auth_token_provider: &dyn DynAuthTokenProvider
+) -> Result<GlobalChannel, SecureLinkError> {
+
+    let mut last_error: Option<SecureLinkError> = None;
+
+    let mut auth_token = auth_token_provider.auth_token_boxed(false).await?;
+    let mut auth_token_refreshed = false;
+
+    for (index, endpoint) in endpoints.connection_order() {
+
+        // Resolved on every attempt, the server may have moved while we were disconnected
+        let socket_addrs = match resolve_domain(&endpoint.host, endpoint.port).await {
+            Ok(socket_addrs) => socket_addrs,
+            Err(err) => {
+                error!("Unable to resolve server address {}: {}", endpoint, err);
+                if last_error.as_ref().is_none_or(SecureLinkError::is_retryable) {
+                    last_error = Some(SecureLinkError::ResolveError(Box::new(err)));
+                }
+                continue;
+            }
+        };
+
+        let mut create_global_channel_result =
+            GlobalChannel::create_global_channel(&socket_addrs, endpoint.host.clone(), config.clone(), auth_token.clone()).await;
+
+        // The token may have expired or been rotated, a fresh one gets a single retry
+        if matches!(create_global_channel_result, Err(SecureLinkError::UnauthorizedError)) && !auth_token_refreshed {
+
+            info!("join denied by {}, refreshing auth token", endpoint);
+
+            auth_token_refreshed = true;
+
+            match auth_token_provider.auth_token_boxed(true).await {
+                Ok(refreshed_auth_token) => {
+                    auth_token = refreshed_auth_token;
+                    create_global_channel_result =
+                        GlobalChannel::create_global_channel(&socket_addrs, endpoint.host.clone(), config.clone(), auth_token.clone()).await;
+                }
+                Err(err) => {
+                    // The denied join is what gets reported
+                    error!("failed to refresh auth token: {}", err);
+                }
+            }
+        }
+
+        match create_global_channel_result {
+            Ok(global_channel) => {
+                info!("joined secure link server {}", endpoint);
+                endpoints.mark_healthy(index);
+                return Ok(global_channel);
+            }
+            Err(err) => {
+                warn!("failed to join secure link server {}: {}", endpoint, err);
+                if last_error.as_ref().is_none_or(SecureLinkError::is_retryable) || !err.is_retryable() {
+                    last_error = Some(err);
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or(SecureLinkError::BadHostError))
+}
@@ -0,0 +1,459 @@
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::{ClientConfig, RootCertStore};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::CertificateDer;
+use tokio::sync::broadcast;
+use crate::auth_token_provider::{AuthTokenProvider, DynAuthTokenProvider, StaticAuthToken};
+use crate::client_certificate::{ClientCertificate, ClientCertificateResolver};
+use crate::spki_pinning::{PinnedServerCertVerifier, SpkiPins};
+use crate::trust_anchors::TrustAnchorSource;
+use crate::dev_cert_report::DevCertificateReport;
+use crate::connector::{Connector, DynConnector, TcpConnector};
+use crate::cs_global_chanel_sender::CurrentGlobalChannelSender;
+use crate::protocol::frame_codec::FrameCodec;
+use crate::protocol::protocol_version::CURRENT_PROTOCOL_VERSION;
+use crate::health_check::RttRecorder;
+use crate::reconnect_policy::ReconnectPolicy;
+use crate::proxy_channel_registry::ProxyChannelRegistry;
+use crate::traffic_stats::TrafficAccounting;
+use crate::rate_limit::{BandwidthLimit, RateLimiters};
+use crate::proxy_channel_limits::{ProxyChannelLimiter, ProxyChannelLimits};
+use crate::destination_policy::{AllowAllDestinations, DestinationPolicy};
+use crate::secure_link::SecureLink;
+use crate::secure_link_config::SecureLinkConfig;
+use crate::secure_link_endpoint::{EndpointSelection, SecureLinkEndpoint, SecureLinkEndpoints};
+use crate::secure_link_event::SecureLinkEvent;
+use crate::SecureLinkError;
+
+pub struct SecureLinkBuilder {
+    endpoints: Vec<SecureLinkEndpoint>,
+    endpoint_selection: EndpointSelection,
+    auth_token_provider: Arc<dyn DynAuthTokenProvider>,
+    tls_config: Option<Arc<ClientConfig>>,
+    extra_root_certificates: Vec<CertificateDer<'static>>,
+    trust_anchor_sources: Vec<TrustAnchorSource>,
+    use_webpki_roots: bool,
+    client_certificate: Option<ClientCertificate>,
+    spki_pins: Option<SpkiPins>,
+    connect_timeout: Duration,
+    tls_handshake_timeout: Duration,
+    health_check_idle_period: Duration,
+    health_check_timeout: Duration,
+    health_check_max_misses: u32,
+    proxy_channel_join_retry: ReconnectPolicy,
+    proxy_channel_idle_timeout: Option<Duration>,
+    proxy_channel_max_lifetime: Option<Duration>,
+    proxy_channel_linger_timeout: Option<Duration>,
+    bandwidth_limit: BandwidthLimit,
+    destination_bandwidth_limit: BandwidthLimit,
+    proxy_channel_bandwidth_limit: BandwidthLimit,
+    proxy_channel_limits: ProxyChannelLimits,
+    max_frame_size: u32,
+    frame_read_timeout: Duration,
+    max_protocol_version: u8,
+    destination_policy: Arc<dyn DestinationPolicy>,
+    connector: Option<Arc<dyn DynConnector>>,
+    events: broadcast::Sender<SecureLinkEvent>
+}
+
+impl SecureLinkBuilder {
+
+    const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+    const DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+    const DEFAULT_HEALTH_CHECK_IDLE_PERIOD_SECS: u64 = 5;
+    const DEFAULT_HEALTH_CHECK_TIMEOUT_SECS: u64 = 10;
+    const DEFAULT_HEALTH_CHECK_MAX_MISSES: u32 = 3;
+    const DEFAULT_PROXY_CHANNEL_JOIN_RETRY_DELAY_MILLIS: u64 = 250;
+    const DEFAULT_PROXY_CHANNEL_JOIN_RETRIES: u32 = 3;
+    const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+    const DEFAULT_FRAME_READ_TIMEOUT_SECS: u64 = 30;
+    const DEFAULT_EVENT_CHANNEL_CAPACITY: usize = 256;
+
+    pub fn new(secure_link_server_host: &str, secure_link_server_port: u16, auth_token: &str) -> Self {
+        Self::with_endpoints(
+            vec![SecureLinkEndpoint::new(secure_link_server_host, secure_link_server_port)],
+            auth_token
+        )
+    }
+
+    /// `connect` fails with `BadHostError` if no endpoint is configured.
+    pub fn with_endpoints(endpoints: Vec<SecureLinkEndpoint>, auth_token: &str) -> Self {
+        Self::with_auth_token_provider(endpoints, StaticAuthToken::new(auth_token))
+    }
+
+    /// Like `with_endpoints`, for a token that is fetched on every join.
+    pub fn with_auth_token_provider(endpoints: Vec<SecureLinkEndpoint>, auth_token_provider: impl AuthTokenProvider) -> Self {
+        SecureLinkBuilder {
+            endpoints,
+            endpoint_selection: EndpointSelection::default(),
+            auth_token_provider: Arc::new(auth_token_provider),
+            tls_config: None,
+            extra_root_certificates: Vec::new(),
+            trust_anchor_sources: Vec::new(),
+            use_webpki_roots: true,
+            client_certificate: None,
+            spki_pins: None,
+            connect_timeout: Duration::from_secs(Self::DEFAULT_CONNECT_TIMEOUT_SECS),
+            tls_handshake_timeout: Duration::from_secs(Self::DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS),
+            health_check_idle_period: Duration::from_secs(Self::DEFAULT_HEALTH_CHECK_IDLE_PERIOD_SECS),
+            health_check_timeout: Duration::from_secs(Self::DEFAULT_HEALTH_CHECK_TIMEOUT_SECS),
+            health_check_max_misses: Self::DEFAULT_HEALTH_CHECK_MAX_MISSES,
+            proxy_channel_join_retry: ReconnectPolicy {
+                initial_delay: Duration::from_millis(Self::DEFAULT_PROXY_CHANNEL_JOIN_RETRY_DELAY_MILLIS),
+                max_delay: Duration::from_secs(2),
+                max_attempts: Some(Self::DEFAULT_PROXY_CHANNEL_JOIN_RETRIES),
+                ..ReconnectPolicy::default()
+            },
+            proxy_channel_idle_timeout: None,
+            proxy_channel_max_lifetime: None,
+            proxy_channel_linger_timeout: None,
+            bandwidth_limit: BandwidthLimit::default(),
+            destination_bandwidth_limit: BandwidthLimit::default(),
+            proxy_channel_bandwidth_limit: BandwidthLimit::default(),
+            proxy_channel_limits: ProxyChannelLimits::default(),
+            max_frame_size: Self::DEFAULT_MAX_FRAME_SIZE,
+            frame_read_timeout: Duration::from_secs(Self::DEFAULT_FRAME_READ_TIMEOUT_SECS),
+            max_protocol_version: CURRENT_PROTOCOL_VERSION,
+            destination_policy: Arc::new(AllowAllDestinations),
+            connector: None,
+            events: broadcast::channel(Self::DEFAULT_EVENT_CHANNEL_CAPACITY).0
+        }
+    }
+
+    /// Replaces the token passed to the constructor with one that is fetched on every join.
+    pub fn auth_token_provider(mut self, auth_token_provider: impl AuthTokenProvider) -> Self {
+        self.auth_token_provider = Arc::new(auth_token_provider);
+        self
+    }
+
+    /// Adds an endpoint to fail over to when the previous ones cannot be joined.
+    pub fn add_endpoint(mut self, endpoint: SecureLinkEndpoint) -> Self {
+        self.endpoints.push(endpoint);
+        self
+    }
+
+    pub fn endpoint_selection(mut self, endpoint_selection: EndpointSelection) -> Self {
+        self.endpoint_selection = endpoint_selection;
+        self
+    }
+
+    /// Replaces the default webpki based client config, dev certificates are not loaded then.
+    /// `connect` fails if root certificates, trust anchors, `use_webpki_roots(false)`, a client
+    /// certificate or server certificate pins are configured as well, the custom config has to
+    /// carry them itself.
+    pub fn tls_config(mut self, tls_config: Arc<ClientConfig>) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    pub fn add_root_certificate(mut self, certificate: CertificateDer<'static>) -> Self {
+        self.extra_root_certificates.push(certificate);
+        self
+    }
+
+    /// Trusts the certificates found at `source` in addition to the bundled roots. A source
+    /// that cannot be read fails `connect`.
+    pub fn add_trust_anchors(mut self, source: TrustAnchorSource) -> Self {
+        self.trust_anchor_sources.push(source);
+        self
+    }
+
+    /// Disables the bundled webpki roots, e.g. for a private PKI where only the configured
+    /// trust anchors should be accepted.
+    pub fn use_webpki_roots(mut self, use_webpki_roots: bool) -> Self {
+        self.use_webpki_roots = use_webpki_roots;
+        self
+    }
+
+    /// Presents a client certificate on the global channel and on every proxy channel,
+    /// in addition to the auth token.
+    pub fn client_certificate(mut self, client_certificate: ClientCertificate) -> Self {
+        self.client_certificate = Some(client_certificate);
+        self
+    }
+
+    /// Only accepts a secure link server whose certificate chain carries one of the pinned keys,
+    /// checked after the regular certificate validation.
+    pub fn server_certificate_pins(mut self, spki_pins: SpkiPins) -> Self {
+        self.spki_pins = Some(spki_pins);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn tls_handshake_timeout(mut self, tls_handshake_timeout: Duration) -> Self {
+        self.tls_handshake_timeout = tls_handshake_timeout;
+        self
+    }
+
+    /// A health check is only sent once nothing was received from the server for this long.
+    pub fn health_check_idle_period(mut self, health_check_idle_period: Duration) -> Self {
+        self.health_check_idle_period = health_check_idle_period;
+        self
+    }
+
+    pub fn health_check_timeout(mut self, health_check_timeout: Duration) -> Self {
+        self.health_check_timeout = health_check_timeout;
+        self
+    }
+
+    /// Consecutive unanswered health checks before the connection is declared lost.
+    pub fn health_check_max_misses(mut self, health_check_max_misses: u32) -> Self {
+        self.health_check_max_misses = health_check_max_misses.max(1);
+        self
+    }
+
+    /// Retries for TCP and TLS failures while a proxy channel connects to the secure link server.
+    /// Nothing is retried once the channel token was sent, it may be single use.
+    pub fn proxy_channel_join_retry(mut self, proxy_channel_join_retry: ReconnectPolicy) -> Self {
+        self.proxy_channel_join_retry = proxy_channel_join_retry;
+        self
+    }
+
+    /// Closes a proxy channel once no data moved in either direction for this long.
+    pub fn proxy_channel_idle_timeout(mut self, proxy_channel_idle_timeout: Duration) -> Self {
+        self.proxy_channel_idle_timeout = Some(proxy_channel_idle_timeout);
+        self
+    }
+
+    /// Closes a proxy channel this long after it joined, whether it is busy or not.
+    pub fn proxy_channel_max_lifetime(mut self, proxy_channel_max_lifetime: Duration) -> Self {
+        self.proxy_channel_max_lifetime = Some(proxy_channel_max_lifetime);
+        self
+    }
+
+    /// Once one direction of a proxy channel finished, the other one is closed after going
+    /// quiet for this long. Off by default, a half-closed channel may legitimately wait a long
+    /// time for its response, e.g. a slow query sent before the client's FIN.
+    pub fn proxy_channel_linger_timeout(mut self, proxy_channel_linger_timeout: Duration) -> Self {
+        self.proxy_channel_linger_timeout = Some(proxy_channel_linger_timeout);
+        self
+    }
+
+    /// Caps the traffic of all proxy channels together.
+    pub fn bandwidth_limit(mut self, bandwidth_limit: BandwidthLimit) -> Self {
+        self.bandwidth_limit = bandwidth_limit;
+        self
+    }
+
+    /// Caps the traffic of all proxy channels to the same destination, each destination
+    /// gets its own budget.
+    pub fn destination_bandwidth_limit(mut self, destination_bandwidth_limit: BandwidthLimit) -> Self {
+        self.destination_bandwidth_limit = destination_bandwidth_limit;
+        self
+    }
+
+    pub fn proxy_channel_bandwidth_limit(mut self, proxy_channel_bandwidth_limit: BandwidthLimit) -> Self {
+        self.proxy_channel_bandwidth_limit = proxy_channel_bandwidth_limit;
+        self
+    }
+
+    /// Open requests beyond these caps are answered with `client_at_capacity`.
+    pub fn proxy_channel_limits(mut self, proxy_channel_limits: ProxyChannelLimits) -> Self {
+        self.proxy_channel_limits = proxy_channel_limits;
+        self
+    }
+
+    /// Frames announcing a larger payload are rejected before anything is allocated.
+    pub fn max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn frame_read_timeout(mut self, frame_read_timeout: Duration) -> Self {
+        self.frame_read_timeout = frame_read_timeout;
+        self
+    }
+
+    /// Caps the version offered during the join, `LEGACY_PROTOCOL_VERSION` disables negotiation.
+    pub fn max_protocol_version(mut self, max_protocol_version: u8) -> Self {
+        self.max_protocol_version = max_protocol_version.min(CURRENT_PROTOCOL_VERSION);
+        self
+    }
+
+    pub fn destination_policy(mut self, destination_policy: impl DestinationPolicy + 'static) -> Self {
+        self.destination_policy = Arc::new(destination_policy);
+        self
+    }
+
+    /// Replaces the default `TcpConnector`. The destination policy is applied before any
+    /// connector runs, the connector only receives the addresses it allowed, or none when it
+    /// allowed the destination by name.
+    pub fn connector(mut self, connector: impl Connector) -> Self {
+        self.connector = Some(Arc::new(connector));
+        self
+    }
+
+    pub fn event_channel_capacity(mut self, event_channel_capacity: usize) -> Self {
+        self.events = broadcast::channel(event_channel_capacity).0;
+        self
+    }
+
+    /// Subscribing before `connect` also delivers the events of the initial connection.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SecureLinkEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn connect(self) -> Result<SecureLink, SecureLinkError> {
+        self.connect_with_config(None).await
+    }
+
+    /// Like `connect`, but retries the first connection with `reconnect_policy` while the
+    /// secure link server is unreachable. Configuration errors still fail right away.
+    pub async fn connect_with_reconnect(self, reconnect_policy: &ReconnectPolicy) -> Result<SecureLink, SecureLinkError> {
+        self.connect_with_config(Some(reconnect_policy)).await
+    }
+
+    async fn connect_with_config(self, reconnect_policy: Option<&ReconnectPolicy>) -> Result<SecureLink, SecureLinkError> {
+
+        if self.endpoints.is_empty() {
+            log::error!("no secure link server endpoint configured");
+            return Err(SecureLinkError::BadHostError);
+        }
+
+        if self.tls_config.is_some() {
+            self.check_custom_tls_config_conflicts()?;
+        }
+
+        let (tls_config, dev_certificate_report) = match self.tls_config {
+            Some(tls_config) => (tls_config, None),
+            None => {
+                let (root_cert_store, dev_certificate_report) = self.build_root_cert_store().await?;
+                let tls_config = Self::build_default_tls_config(root_cert_store, self.client_certificate, self.spki_pins)?;
+                (Arc::new(tls_config), dev_certificate_report)
+            }
+        };
+
+        let connector = self.connector
+            .unwrap_or_else(|| Arc::new(TcpConnector::new()));
+
+        let config = SecureLinkConfig {
+            tls_config,
+            connect_timeout: self.connect_timeout,
+            tls_handshake_timeout: self.tls_handshake_timeout,
+            health_check_idle_period: self.health_check_idle_period,
+            health_check_timeout: self.health_check_timeout,
+            health_check_max_misses: self.health_check_max_misses,
+            proxy_channel_join_retry: self.proxy_channel_join_retry,
+            proxy_channel_idle_timeout: self.proxy_channel_idle_timeout,
+            proxy_channel_max_lifetime: self.proxy_channel_max_lifetime,
+            proxy_channel_linger_timeout: self.proxy_channel_linger_timeout,
+            frame_codec: FrameCodec::new(self.max_frame_size, self.frame_read_timeout),
+            max_protocol_version: self.max_protocol_version,
+            destination_policy: self.destination_policy,
+            connector,
+            events: self.events,
+            health_check_rtt: RttRecorder::new(),
+            proxy_channel_registry: Arc::new(ProxyChannelRegistry::default()),
+            current_global_channel_sender: CurrentGlobalChannelSender::default(),
+            traffic_accounting: TrafficAccounting::new(),
+            rate_limiters: RateLimiters::new(
+                self.bandwidth_limit,
+                self.destination_bandwidth_limit,
+                self.proxy_channel_bandwidth_limit
+            ),
+            proxy_channel_limiter: Arc::new(ProxyChannelLimiter::new(self.proxy_channel_limits)),
+            dev_certificate_report
+        };
+
+        SecureLink::connect_with_config(
+            SecureLinkEndpoints::new(self.endpoints, self.endpoint_selection),
+            self.auth_token_provider,
+            Arc::new(config),
+            reconnect_policy
+        ).await
+
+    }
+
+    /// Options that only shape the default TLS config would silently not apply to a custom one.
+    fn check_custom_tls_config_conflicts(&self) -> Result<(), SecureLinkError> {
+
+        let conflicting_options: Vec<&str> = [
+            (!self.extra_root_certificates.is_empty(), "add_root_certificate"),
+            (!self.trust_anchor_sources.is_empty(), "add_trust_anchors"),
+            (!self.use_webpki_roots, "use_webpki_roots"),
+            (self.client_certificate.is_some(), "client_certificate"),
+            (self.spki_pins.is_some(), "server_certificate_pins")
+        ]
+            .into_iter()
+            .filter_map(|(configured, option)| configured.then_some(option))
+            .collect();
+
+        match conflicting_options.is_empty() {
+            true => Ok(()),
+            false => Err(SecureLinkError::ConflictingTlsOptions(format!(
+                "{} cannot be combined with tls_config",
+                conflicting_options.join(", ")
+            )))
+        }
+    }
+
+    async fn build_root_cert_store(&self) -> Result<(RootCertStore, Option<DevCertificateReport>), SecureLinkError> {
+
+        let mut root_cert_store = RootCertStore::empty();
+
+        if self.use_webpki_roots {
+            root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+
+        #[cfg(feature = "load_dev_certs")]
+        let dev_certificate_report = Some(crate::dev_cert_loader::DevCertLoader::load_dev_certs(&mut root_cert_store).await?);
+
+        #[cfg(not(feature = "load_dev_certs"))]
+        let dev_certificate_report = None;
+
+        for trust_anchor_source in &self.trust_anchor_sources {
+            trust_anchor_source.load_into(&mut root_cert_store).await?;
+        }
+
+        for certificate in &self.extra_root_certificates {
+            if let Err(e) = root_cert_store.add(certificate.clone()) {
+                log::warn!("Failed to add root certificate: {}", e);
+            }
+        }
+
+        if root_cert_store.is_empty() {
+            return Err(SecureLinkError::TrustAnchorError("no trust anchors configured".to_string()));
+        }
+
+        Ok((root_cert_store, dev_certificate_report))
+    }
+
+    fn build_default_tls_config(
+        root_cert_store: RootCertStore,
+        client_certificate: Option<ClientCertificate>,
+        spki_pins: Option<SpkiPins>
+    ) -> Result<ClientConfig, SecureLinkError> {
+
+        let tls_config_builder = match spki_pins {
+            Some(spki_pins) => {
+
+                spki_pins.validate()?;
+
+                let webpki_verifier = WebPkiServerVerifier::builder(Arc::new(root_cert_store))
+                    .build()
+                    .map_err(|err| SecureLinkError::TlsStreamError(Box::new(err)))?;
+
+                ClientConfig::builder()
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedServerCertVerifier::new(webpki_verifier, spki_pins)))
+            }
+            None => ClientConfig::builder().with_root_certificates(root_cert_store)
+        };
+
+        let tls_config = match client_certificate {
+            Some(client_certificate) => {
+                tls_config_builder.with_client_cert_resolver(Arc::new(ClientCertificateResolver::new(client_certificate)?))
+            }
+            None => tls_config_builder.with_no_client_auth()
+        };
+
+        Ok(tls_config)
+
+    }
+
+}
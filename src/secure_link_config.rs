@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::ClientConfig;
+use tokio::sync::broadcast;
+use crate::connector::DynConnector;
+use crate::cs_global_chanel_sender::CurrentGlobalChannelSender;
+use crate::destination_policy::DestinationPolicy;
+use crate::health_check::RttRecorder;
+use crate::reconnect_policy::ReconnectPolicy;
+use crate::proxy_channel_registry::ProxyChannelRegistry;
+use crate::traffic_stats::TrafficAccounting;
+use crate::rate_limit::RateLimiters;
+use crate::proxy_channel_limits::ProxyChannelLimiter;
+use crate::dev_cert_report::DevCertificateReport;
+use crate::protocol::frame_codec::FrameCodec;
+use crate::secure_link_event::SecureLinkEvent;
+
+pub(crate) struct SecureLinkConfig {
+    pub tls_config: Arc<ClientConfig>,
+    pub connect_timeout: Duration,
+    pub tls_handshake_timeout: Duration,
+    pub health_check_idle_period: Duration,
+    pub health_check_timeout: Duration,
+    pub health_check_max_misses: u32,
+    pub proxy_channel_join_retry: ReconnectPolicy,
+    pub proxy_channel_idle_timeout: Option<Duration>,
+    pub proxy_channel_max_lifetime: Option<Duration>,
+    pub proxy_channel_linger_timeout: Option<Duration>,
+    pub frame_codec: FrameCodec,
+    pub max_protocol_version: u8,
+    pub destination_policy: Arc<dyn DestinationPolicy>,
+    pub connector: Arc<dyn DynConnector>,
+    pub events: broadcast::Sender<SecureLinkEvent>,
+    pub health_check_rtt: RttRecorder,
+    pub proxy_channel_registry: Arc<ProxyChannelRegistry>,
+    pub current_global_channel_sender: CurrentGlobalChannelSender,
+    pub traffic_accounting: TrafficAccounting,
+    pub rate_limiters: RateLimiters,
+    pub proxy_channel_limiter: Arc<ProxyChannelLimiter>,
+    pub dev_certificate_report: Option<DevCertificateReport>
+}
+
+impl SecureLinkConfig {
+
+    pub fn emit(&self, event: SecureLinkEvent) {
+        // No subscribers is not an error
+        let _ = self.events.send(event);
+    }
+}
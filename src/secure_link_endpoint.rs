@@ -0,0 +1,152 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use crate::reconnect_policy::random_unit;
+use crate::SecureLinkError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecureLinkEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub weight: u32
+}
+
+impl SecureLinkEndpoint {
+
+    pub fn new(host: &str, port: u16) -> Self {
+        SecureLinkEndpoint {
+            host: host.to_string(),
+            port,
+            weight: 1
+        }
+    }
+
+    /// Relative share of connections with `EndpointSelection::Weighted`. Endpoints with weight 0
+    /// are a last resort, only tried in configured order once every weighted endpoint failed.
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+}
+
+impl fmt::Display for SecureLinkEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Parses `host:port` with an optional `@weight` suffix, IPv6 hosts are written as `[::1]:443`.
+impl FromStr for SecureLinkEndpoint {
+    type Err = SecureLinkError;
+
+    fn from_str(endpoint: &str) -> Result<Self, Self::Err> {
+
+        let endpoint = endpoint.trim();
+
+        let (address, weight) = match endpoint.split_once('@') {
+            Some((address, weight)) => {
+                let weight = weight.parse::<u32>().map_err(|_| SecureLinkError::BadHostError)?;
+                (address, weight)
+            }
+            None => (endpoint, 1)
+        };
+
+        let (host, port) = address.rsplit_once(':').ok_or(SecureLinkError::BadHostError)?;
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = port.parse::<u16>().map_err(|_| SecureLinkError::BadHostError)?;
+
+        if host.is_empty() {
+            return Err(SecureLinkError::BadHostError);
+        }
+
+        Ok(SecureLinkEndpoint::new(host, port).weight(weight))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EndpointSelection {
+    /// Endpoints are tried in the order they were configured.
+    #[default]
+    Ordered,
+    /// Endpoints are tried in a random order biased by their weight.
+    Weighted
+}
+
+pub(crate) struct SecureLinkEndpoints {
+    endpoints: Vec<SecureLinkEndpoint>,
+    selection: EndpointSelection,
+    healthy_endpoint: Mutex<Option<usize>>
+}
+
+impl SecureLinkEndpoints {
+
+    pub fn new(endpoints: Vec<SecureLinkEndpoint>, selection: EndpointSelection) -> Self {
+        SecureLinkEndpoints {
+            endpoints,
+            selection,
+            healthy_endpoint: Mutex::new(None)
+        }
+    }
+
+    /// The last endpoint that accepted a join goes first, the others follow by selection.
+    pub fn connection_order(&self) -> Vec<(usize, &SecureLinkEndpoint)> {
+
+        let mut indices: Vec<usize> = (0..self.endpoints.len()).collect();
+
+        if self.selection == EndpointSelection::Weighted {
+            indices = self.weighted_order(indices);
+        }
+
+        if let Some(healthy_endpoint) = *self.healthy_endpoint.lock().unwrap() {
+            if let Some(position) = indices.iter().position(|index| *index == healthy_endpoint) {
+                let index = indices.remove(position);
+                indices.insert(0, index);
+            }
+        }
+
+        indices.into_iter().map(|index| (index, &self.endpoints[index])).collect()
+    }
+
+    pub fn mark_healthy(&self, index: usize) {
+        *self.healthy_endpoint.lock().unwrap() = Some(index);
+    }
+
+    pub fn healthy_endpoint(&self) -> Option<SecureLinkEndpoint> {
+        self.healthy_endpoint.lock().unwrap().map(|index| self.endpoints[index].clone())
+    }
+
+    fn weighted_order(&self, indices: Vec<usize>) -> Vec<usize> {
+
+        let (mut remaining, last_resort): (Vec<usize>, Vec<usize>) =
+            indices.into_iter().partition(|index| self.endpoints[*index].weight > 0);
+
+        let mut ordered = Vec::with_capacity(remaining.len() + last_resort.len());
+
+        while !remaining.is_empty() {
+
+            let total_weight: u64 = remaining.iter().map(|index| self.endpoints[*index].weight as u64).sum();
+            let mut pick = (random_unit() * total_weight as f64) as u64;
+
+            let position = remaining.iter().position(|index| {
+                let weight = self.endpoints[*index].weight as u64;
+                if pick < weight {
+                    true
+                } else {
+                    pick -= weight;
+                    false
+                }
+            }).unwrap_or(remaining.len() - 1);
+
+            ordered.push(remaining.remove(position));
+        }
+
+        ordered.extend(last_resort);
+
+        ordered
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+use crate::protocol::global_channel_message::{ProxyChannelCloseReason, ProxyDestination};
+use crate::protocol::protocol_version::NegotiatedProtocol;
+
+#[derive(Debug, Clone)]
+pub enum SecureLinkEvent {
+    GlobalChannelConnected {
+        secure_link_session_id: String,
+        protocol: NegotiatedProtocol
+    },
+    GlobalChannelDisconnected {
+        reason: DisconnectReason
+    },
+    HealthCheckSucceeded {
+        rtt: Duration
+    },
+    HealthCheckFailed {
+        reason: String
+    },
+    ProxyChannelOpenRequested {
+        proxy_channel_id: String,
+        destination: ProxyDestination
+    },
+    DestinationConnectFailed {
+        proxy_channel_id: String,
+        destination: ProxyDestination,
+        error: String
+    },
+    ProxyChannelJoined {
+        proxy_channel_id: String
+    },
+    ProxyChannelJoinFailed {
+        proxy_channel_id: String,
+        error: String
+    },
+    ProxyChannelClosed {
+        proxy_channel_id: String,
+        bytes_uploaded: u64,
+        bytes_downloaded: u64,
+        duration: Duration,
+        reason: ProxyChannelCloseReason,
+        error: Option<String>
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    Shutdown,
+    ConnectionLost(String),
+    ProtocolError(String)
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShutdownState {
+    Running,
+    Draining(Duration),
+    /// Every proxy channel ended or was stopped, the global channel can leave.
+    Drained
+}
+
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<ShutdownState>>);
+
+impl ShutdownHandle {
+
+    pub(crate) fn new() -> Self {
+        let (sender, _) = watch::channel(ShutdownState::Running);
+        ShutdownHandle(Arc::new(sender))
+    }
+
+    /// Stops accepting new proxy channels and lets active ones finish for up to `drain_timeout`
+    /// before the message loop returns.
+    pub fn shutdown(&self, drain_timeout: Duration) {
+        self.0.send_if_modified(|state| match state {
+            ShutdownState::Running => {
+                *state = ShutdownState::Draining(drain_timeout);
+                true
+            }
+            _ => false
+        });
+    }
+
+    pub fn is_shutdown_requested(&self) -> bool {
+        *self.0.borrow() != ShutdownState::Running
+    }
+
+    pub(crate) fn drained(&self) {
+        self.0.send_replace(ShutdownState::Drained);
+    }
+
+    pub(crate) fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.0.subscribe())
+    }
+}
+
+pub(crate) struct ShutdownSignal(watch::Receiver<ShutdownState>);
+
+impl ShutdownSignal {
+
+    /// Resolves with the drain timeout once shutdown is requested, immediately if it already was.
+    pub async fn requested(&self) -> Duration {
+        match self.wait_for(|state| state != ShutdownState::Running).await {
+            ShutdownState::Draining(drain_timeout) => drain_timeout,
+            _ => Duration::ZERO
+        }
+    }
+
+    /// Resolves once the proxy channels were drained after a shutdown request.
+    pub async fn drained(&self) {
+        self.wait_for(|state| state == ShutdownState::Drained).await;
+    }
+
+    async fn wait_for(&self, condition: impl Fn(ShutdownState) -> bool) -> ShutdownState {
+        // A clone per wait, so both conditions can be awaited in the same select!
+        let mut receiver = self.0.clone();
+        let state = receiver.wait_for(|state| condition(*state)).await.map(|state| *state);
+        match state {
+            Ok(state) => state,
+            Err(_) => std::future::pending().await
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::sync::Arc;
+use aws_lc_rs::digest::{digest, SHA256};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{error, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+use crate::SecureLinkError;
+
+const SPKI_SHA256_LENGTH: usize = 32;
+
+/// SHA-256 hashes of the SubjectPublicKeyInfo the secure link server may present, in the
+/// base64 form used by `openssl ... | openssl dgst -sha256 -binary | base64`. The handshake
+/// succeeds if the leaf or any chain certificate matches a pin or a backup pin.
+#[derive(Debug, Clone, Default)]
+pub struct SpkiPins {
+    pins: Vec<[u8; SPKI_SHA256_LENGTH]>,
+    backup_pins: Vec<[u8; SPKI_SHA256_LENGTH]>,
+    report_only: bool
+}
+
+impl SpkiPins {
+
+    pub fn new() -> Self {
+        SpkiPins::default()
+    }
+
+    pub fn pin(mut self, spki_sha256_base64: &str) -> Result<Self, SecureLinkError> {
+        self.pins.push(decode_pin(spki_sha256_base64)?);
+        Ok(self)
+    }
+
+    /// A key that is not deployed yet, so a rotation does not lock out clients.
+    pub fn backup_pin(mut self, spki_sha256_base64: &str) -> Result<Self, SecureLinkError> {
+        self.backup_pins.push(decode_pin(spki_sha256_base64)?);
+        Ok(self)
+    }
+
+    /// Mismatches are logged but the handshake is allowed to continue.
+    pub fn report_only(mut self, report_only: bool) -> Self {
+        self.report_only = report_only;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PinCheck {
+    Pinned,
+    BackupPinned,
+    MismatchReported,
+    Mismatch
+}
+
+impl SpkiPins {
+
+    /// An empty pin set would reject every server, or check nothing in report only mode.
+    pub(crate) fn validate(&self) -> Result<(), SecureLinkError> {
+        match self.pins.is_empty() {
+            true => Err(SecureLinkError::InvalidSpkiPin("at least one pin is required".to_string())),
+            false => Ok(())
+        }
+    }
+
+    fn check(&self, spki_hashes: &[[u8; SPKI_SHA256_LENGTH]]) -> PinCheck {
+
+        if spki_hashes.iter().any(|spki_hash| self.pins.contains(spki_hash)) {
+            return PinCheck::Pinned;
+        }
+
+        if spki_hashes.iter().any(|spki_hash| self.backup_pins.contains(spki_hash)) {
+            return PinCheck::BackupPinned;
+        }
+
+        match self.report_only {
+            true => PinCheck::MismatchReported,
+            false => PinCheck::Mismatch
+        }
+    }
+}
+
+fn decode_pin(spki_sha256_base64: &str) -> Result<[u8; SPKI_SHA256_LENGTH], SecureLinkError> {
+    BASE64.decode(spki_sha256_base64.trim())
+        .ok()
+        .and_then(|pin| <[u8; SPKI_SHA256_LENGTH]>::try_from(pin).ok())
+        .ok_or_else(|| SecureLinkError::InvalidSpkiPin(spki_sha256_base64.to_string()))
+}
+
+/// Runs the regular webpki verification first and checks the pins on top of it.
+#[derive(Debug)]
+pub(crate) struct PinnedServerCertVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    spki_pins: SpkiPins
+}
+
+impl PinnedServerCertVerifier {
+
+    pub fn new(inner: Arc<WebPkiServerVerifier>, spki_pins: SpkiPins) -> Self {
+        PinnedServerCertVerifier { inner, spki_pins }
+    }
+}
+
+impl ServerCertVerifier for PinnedServerCertVerifier {
+
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let spki_hashes: Vec<[u8; SPKI_SHA256_LENGTH]> =
+            std::iter::once(end_entity)
+                .chain(intermediates)
+                .filter_map(spki_sha256)
+                .collect();
+
+        let presented = || spki_hashes.iter().map(|spki_hash| BASE64.encode(spki_hash)).collect::<Vec<String>>();
+
+        match self.spki_pins.check(&spki_hashes) {
+            PinCheck::Pinned => Ok(verified),
+            PinCheck::BackupPinned => {
+                warn!("server certificate for {:?} matched a backup pin only", server_name);
+                Ok(verified)
+            }
+            PinCheck::MismatchReported => {
+                warn!("server certificate for {:?} does not match any pin, presented {:?} (report only)", server_name, presented());
+                Ok(verified)
+            }
+            PinCheck::Mismatch => {
+                error!("server certificate for {:?} does not match any pin, presented {:?}", server_name, presented());
+                Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// `None` only for a certificate that cannot be parsed, webpki accepted the chain already.
+fn spki_sha256(certificate: &CertificateDer<'_>) -> Option<[u8; SPKI_SHA256_LENGTH]> {
+
+    let x509 = match x509_parser::parse_x509_certificate(certificate.as_ref()) {
+        Ok((_, x509)) => x509,
+        Err(err) => {
+            warn!("skipping unparsable certificate while checking pins: {}", err);
+            return None;
+        }
+    };
+
+    let mut spki_hash = [0u8; SPKI_SHA256_LENGTH];
+    spki_hash.copy_from_slice(digest(&SHA256, x509.tbs_certificate.subject_pki.raw).as_ref());
+
+    Some(spki_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::pem::PemObject;
+    use super::*;
+
+    // openssl x509 -in dev_certs/localhost.crt -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    const LOCALHOST_PIN: &str = "tN6ZBdXizDFDl+PXVDGgnC45g9C5Fy7XTUPrWSeMG7M=";
+
+    const LEAF: [u8; SPKI_SHA256_LENGTH] = [1; SPKI_SHA256_LENGTH];
+    const INTERMEDIATE: [u8; SPKI_SHA256_LENGTH] = [2; SPKI_SHA256_LENGTH];
+    const OTHER: [u8; SPKI_SHA256_LENGTH] = [3; SPKI_SHA256_LENGTH];
+
+    fn pin_of(spki_hash: &[u8; SPKI_SHA256_LENGTH]) -> String {
+        BASE64.encode(spki_hash)
+    }
+
+    #[test]
+    fn decodes_valid_pin() {
+        assert_eq!(decode_pin(&pin_of(&LEAF)).unwrap(), LEAF);
+        assert_eq!(decode_pin(&format!(" {}\n", pin_of(&LEAF))).unwrap(), LEAF);
+    }
+
+    #[test]
+    fn rejects_pin_of_wrong_length() {
+        assert!(matches!(decode_pin(&BASE64.encode([1u8; 20])), Err(SecureLinkError::InvalidSpkiPin(_))));
+        assert!(matches!(decode_pin(&BASE64.encode([1u8; 33])), Err(SecureLinkError::InvalidSpkiPin(_))));
+        assert!(matches!(decode_pin(""), Err(SecureLinkError::InvalidSpkiPin(_))));
+    }
+
+    #[test]
+    fn rejects_bad_base64() {
+        assert!(matches!(decode_pin("not base64!"), Err(SecureLinkError::InvalidSpkiPin(_))));
+        assert!(matches!(decode_pin(&pin_of(&LEAF).replace('=', "")), Err(SecureLinkError::InvalidSpkiPin(_))));
+    }
+
+    #[test]
+    fn hashes_subject_public_key_info() {
+        let certificate = CertificateDer::from_pem_slice(include_bytes!("../dev_certs/localhost.crt")).unwrap();
+        assert_eq!(spki_sha256(&certificate), Some(decode_pin(LOCALHOST_PIN).unwrap()));
+    }
+
+    #[test]
+    fn accepts_leaf_match() {
+        let spki_pins = SpkiPins::new().pin(&pin_of(&LEAF)).unwrap();
+        assert_eq!(spki_pins.check(&[LEAF, INTERMEDIATE]), PinCheck::Pinned);
+    }
+
+    #[test]
+    fn accepts_intermediate_match() {
+        let spki_pins = SpkiPins::new().pin(&pin_of(&INTERMEDIATE)).unwrap();
+        assert_eq!(spki_pins.check(&[LEAF, INTERMEDIATE]), PinCheck::Pinned);
+    }
+
+    #[test]
+    fn accepts_backup_only_match() {
+        let spki_pins = SpkiPins::new()
+            .pin(&pin_of(&OTHER)).unwrap()
+            .backup_pin(&pin_of(&LEAF)).unwrap();
+        assert_eq!(spki_pins.check(&[LEAF, INTERMEDIATE]), PinCheck::BackupPinned);
+    }
+
+    #[test]
+    fn rejects_mismatch() {
+        let spki_pins = SpkiPins::new().pin(&pin_of(&OTHER)).unwrap();
+        assert_eq!(spki_pins.check(&[LEAF, INTERMEDIATE]), PinCheck::Mismatch);
+        assert_eq!(spki_pins.check(&[]), PinCheck::Mismatch);
+    }
+
+    #[test]
+    fn report_only_lets_mismatch_pass() {
+        let spki_pins = SpkiPins::new().pin(&pin_of(&OTHER)).unwrap().report_only(true);
+        assert_eq!(spki_pins.check(&[LEAF, INTERMEDIATE]), PinCheck::MismatchReported);
+        assert_eq!(spki_pins.report_only(true).pin(&pin_of(&LEAF)).unwrap().check(&[LEAF]), PinCheck::Pinned);
+    }
+
+    #[test]
+    fn requires_at_least_one_pin() {
+        assert!(matches!(SpkiPins::new().validate(), Err(SecureLinkError::InvalidSpkiPin(_))));
+        assert!(matches!(SpkiPins::new().backup_pin(&pin_of(&LEAF)).unwrap().validate(), Err(SecureLinkError::InvalidSpkiPin(_))));
+        assert!(SpkiPins::new().pin(&pin_of(&LEAF)).unwrap().validate().is_ok());
+    }
+}
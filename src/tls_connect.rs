@@ -1,23 +1,126 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
-use log::info;
+use std::time::Duration;
+use anyhow::anyhow;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, info};
 use rustls::ClientConfig;
 use rustls::pki_types::ServerName;
 use tokio::net::TcpStream;
+use tokio::time::timeout;
 use tokio_rustls::{TlsConnector, TlsStream};
 
-pub async fn connect_to_domain(config: Arc<ClientConfig>, socket_addr: SocketAddr, domain: String) -> Result<TlsStream<TcpStream>, anyhow::Error> {
-    
+// RFC 8305 recommended "Connection Attempt Delay"
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+pub async fn resolve_domain(domain: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error> {
+
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((domain, port)).await?.collect();
+
+    if addresses.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} did not resolve to any address", domain)));
+    }
+
+    Ok(addresses)
+}
+
+/// Connects to the first of `socket_addrs` that answers and returns the stream together with
+/// the address that was used.
+pub async fn connect_to_domain(
+    config: Arc<ClientConfig>,
+    socket_addrs: &[SocketAddr],
+    domain: String,
+    connect_timeout: Duration,
+    tls_handshake_timeout: Duration
+) -> Result<(TlsStream<TcpStream>, SocketAddr), anyhow::Error> {
+
     let connector = TlsConnector::from(config);
     let server_name = ServerName::try_from(domain)?;
 
     // Create a TCP connection
-    let tcp_stream = TcpStream::connect(&socket_addr).await?;
-    info!("Connected to the server via TCP");
+    let (tcp_stream, socket_addr) = timeout(connect_timeout, connect_happy_eyeballs(socket_addrs)).await
+        .map_err(|_| anyhow!("TCP connect to {:?} timed out after {:?}", socket_addrs, connect_timeout))??;
+    info!("Connected to the server via TCP at {}", socket_addr);
 
     // Establish a TLS connection
-    let tls_stream = connector.connect(server_name, tcp_stream).await?;
+    let tls_stream = timeout(tls_handshake_timeout, connector.connect(server_name, tcp_stream)).await
+        .map_err(|_| anyhow!("TLS handshake with {} timed out after {:?}", socket_addr, tls_handshake_timeout))??;
     info!("TLS connection established");
 
-    Ok(tls_stream.into())
-}
\ No newline at end of file
+    Ok((tls_stream.into(), socket_addr))
+}
+
+/// Staggered connection racing as described in RFC 8305: address families are interleaved
+/// and the next attempt starts as soon as any attempt failed, or when the most recent one did
+/// not finish within the connection attempt delay.
+async fn connect_happy_eyeballs(socket_addrs: &[SocketAddr]) -> Result<(TcpStream, SocketAddr), anyhow::Error> {
+
+    let mut remaining_addrs = interleave_address_families(socket_addrs).into_iter().peekable();
+    let mut running_attempts = FuturesUnordered::new();
+    let mut last_error: Option<std::io::Error> = None;
+
+    let start_attempt = |socket_addr: SocketAddr| async move {
+        (socket_addr, TcpStream::connect(socket_addr).await)
+    };
+
+    // Only reset when an attempt starts, so the delay counts from the most recent attempt
+    let attempt_delay = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY);
+    tokio::pin!(attempt_delay);
+
+    loop {
+
+        // Every pass follows a failed attempt or an elapsed delay, both start the next address
+        if let Some(socket_addr) = remaining_addrs.next() {
+            running_attempts.push(start_attempt(socket_addr));
+            attempt_delay.as_mut().reset(tokio::time::Instant::now() + CONNECTION_ATTEMPT_DELAY);
+        }
+
+        if running_attempts.is_empty() {
+            break;
+        }
+
+        tokio::select! {
+
+            Some((socket_addr, connect_result)) = running_attempts.next() => {
+                match connect_result {
+                    Ok(tcp_stream) => return Ok((tcp_stream, socket_addr)),
+                    Err(err) => {
+                        debug!("connection attempt to {} failed: {}", socket_addr, err);
+                        last_error = Some(err);
+                    }
+                }
+            }
+
+            _ = &mut attempt_delay, if remaining_addrs.peek().is_some() => {}
+
+        }
+
+    }
+
+    match last_error {
+        Some(err) => Err(err.into()),
+        None => Err(anyhow!("no address to connect to"))
+    }
+}
+
+fn interleave_address_families(socket_addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        socket_addrs.iter().partition(|socket_addr| socket_addr.is_ipv6());
+
+    if preferred.is_empty() {
+        std::mem::swap(&mut preferred, &mut other);
+    }
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(socket_addrs.len());
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second))
+        }
+    }
+}
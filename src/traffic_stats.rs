@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::protocol::global_channel_message::{ProxyChannelCloseReason, ProxyDestination};
+use crate::proxy_channel_registry::ProxyChannelInfo;
+
+/// A proxy channel that has ended, including channels that never joined or failed to open.
+#[derive(Debug, Clone)]
+pub struct ProxyChannelRecord {
+    pub proxy_channel_id: String,
+    pub secure_link_session_id: String,
+    pub destination: ProxyDestination,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+    /// From the open request to the end, like `ProxyChannelInfo::duration`.
+    pub duration: Duration,
+    pub reason: ProxyChannelCloseReason
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrafficTotals {
+    pub proxy_channels: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+    pub duration: Duration
+}
+
+/// Totals include the bytes moved so far by channels that are still active.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficStats {
+    pub total: TrafficTotals,
+    pub per_destination: HashMap<ProxyDestination, TrafficTotals>,
+    pub per_session: HashMap<String, TrafficTotals>,
+    pub closed_by_reason: HashMap<ProxyChannelCloseReason, u64>,
+    pub active_proxy_channels: Vec<ProxyChannelInfo>,
+    /// The most recently closed channels, oldest first.
+    pub recent_proxy_channels: Vec<ProxyChannelRecord>
+}
+
+pub(crate) struct TrafficAccounting(Mutex<TrafficAccountingInner>);
+
+#[derive(Default)]
+struct TrafficAccountingInner {
+    total: TrafficTotals,
+    per_destination: HashMap<ProxyDestination, TrafficTotals>,
+    per_session: HashMap<String, TrafficTotals>,
+    closed_by_reason: HashMap<ProxyChannelCloseReason, u64>,
+    recent_proxy_channels: VecDeque<ProxyChannelRecord>
+}
+
+impl TrafficTotals {
+
+    fn add(&mut self, bytes_uploaded: u64, bytes_downloaded: u64, duration: Duration) {
+        self.proxy_channels += 1;
+        self.bytes_uploaded += bytes_uploaded;
+        self.bytes_downloaded += bytes_downloaded;
+        self.duration += duration;
+    }
+}
+
+impl TrafficAccounting {
+
+    const RECENT_PROXY_CHANNELS: usize = 1000;
+
+    pub fn new() -> Self {
+        TrafficAccounting(Mutex::new(TrafficAccountingInner::default()))
+    }
+
+    pub fn record_closed(&self, record: ProxyChannelRecord) {
+
+        let mut inner = self.0.lock().unwrap();
+
+        let (bytes_uploaded, bytes_downloaded, duration) = (record.bytes_uploaded, record.bytes_downloaded, record.duration);
+
+        inner.total.add(bytes_uploaded, bytes_downloaded, duration);
+        inner.per_destination.entry(record.destination.clone()).or_default().add(bytes_uploaded, bytes_downloaded, duration);
+        inner.per_session.entry(record.secure_link_session_id.clone()).or_default().add(bytes_uploaded, bytes_downloaded, duration);
+        *inner.closed_by_reason.entry(record.reason).or_default() += 1;
+
+        if inner.recent_proxy_channels.len() == Self::RECENT_PROXY_CHANNELS {
+            inner.recent_proxy_channels.pop_front();
+        }
+        inner.recent_proxy_channels.push_back(record);
+    }
+
+    pub fn snapshot(&self, active_proxy_channels: Vec<ProxyChannelInfo>) -> TrafficStats {
+
+        let inner = self.0.lock().unwrap();
+
+        let mut traffic_stats = TrafficStats {
+            total: inner.total.clone(),
+            per_destination: inner.per_destination.clone(),
+            per_session: inner.per_session.clone(),
+            closed_by_reason: inner.closed_by_reason.clone(),
+            active_proxy_channels: Vec::new(),
+            recent_proxy_channels: inner.recent_proxy_channels.iter().cloned().collect()
+        };
+
+        for active_proxy_channel in &active_proxy_channels {
+
+            let (bytes_uploaded, bytes_downloaded, duration) =
+                (active_proxy_channel.bytes_uploaded, active_proxy_channel.bytes_downloaded, active_proxy_channel.duration);
+
+            traffic_stats.total.add(bytes_uploaded, bytes_downloaded, duration);
+            traffic_stats.per_destination.entry(active_proxy_channel.destination.clone()).or_default().add(bytes_uploaded, bytes_downloaded, duration);
+            traffic_stats.per_session.entry(active_proxy_channel.secure_link_session_id.clone()).or_default().add(bytes_uploaded, bytes_downloaded, duration);
+        }
+
+        traffic_stats.active_proxy_channels = active_proxy_channels;
+
+        traffic_stats
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::RootCertStore;
+use crate::SecureLinkError;
+
+/// Where additional trusted root certificates are loaded from when the client is built.
+#[derive(Debug, Clone)]
+pub enum TrustAnchorSource {
+    /// A PEM bundle or a single DER encoded certificate.
+    File(PathBuf),
+    /// Every file in the directory, each loaded like `File`. Unreadable files are skipped.
+    Directory(PathBuf),
+    /// The operating system store: the Windows certificate store, the macOS keychain or the
+    /// distribution bundle on Linux. `SSL_CERT_FILE` and `SSL_CERT_DIR` take precedence.
+    SystemStore
+}
+
+impl TrustAnchorSource {
+
+    /// Returns how many certificates were added.
+    pub(crate) async fn load_into(&self, root_cert_store: &mut RootCertStore) -> Result<usize, SecureLinkError> {
+
+        let added = match self {
+            TrustAnchorSource::File(path) => load_file(path, root_cert_store).await?,
+            TrustAnchorSource::Directory(path) => load_directory(path, root_cert_store).await?,
+            TrustAnchorSource::SystemStore => load_system_store(root_cert_store).await?
+        };
+
+        info!("loaded {} trust anchors from {:?}", added, self);
+
+        Ok(added)
+    }
+}
+
+async fn load_file(path: &Path, root_cert_store: &mut RootCertStore) -> Result<usize, SecureLinkError> {
+
+    let data = tokio::fs::read(path).await
+        .map_err(|err| SecureLinkError::TrustAnchorError(format!("{}: {}", path.display(), err)))?;
+
+    let certificates: Vec<CertificateDer<'static>> = match data.windows(10).any(|window| window == b"-----BEGIN") {
+        true => CertificateDer::pem_slice_iter(&data)
+            .filter_map(|certificate| match certificate {
+                Ok(certificate) => Some(certificate),
+                Err(err) => {
+                    warn!("skipping unreadable PEM block in {}: {:?}", path.display(), err);
+                    None
+                }
+            })
+            .collect(),
+        false => vec![CertificateDer::from(data)]
+    };
+
+    let (added, ignored) = root_cert_store.add_parsable_certificates(certificates);
+
+    if ignored > 0 {
+        warn!("ignored {} invalid certificates in {}", ignored, path.display());
+    }
+
+    Ok(added)
+}
+
+async fn load_directory(path: &Path, root_cert_store: &mut RootCertStore) -> Result<usize, SecureLinkError> {
+
+    let mut entries = tokio::fs::read_dir(path).await
+        .map_err(|err| SecureLinkError::TrustAnchorError(format!("{}: {}", path.display(), err)))?;
+
+    let mut added = 0;
+
+    while let Some(entry) = entries.next_entry().await
+        .map_err(|err| SecureLinkError::TrustAnchorError(format!("{}: {}", path.display(), err)))? {
+
+        let entry_path = entry.path();
+
+        // Follows symlinks, c_rehash style directories consist of them
+        if !tokio::fs::metadata(&entry_path).await.is_ok_and(|metadata| metadata.is_file()) {
+            continue;
+        }
+
+        match load_file(&entry_path, root_cert_store).await {
+            Ok(added_from_file) => added += added_from_file,
+            Err(err) => warn!("skipping trust anchor file: {}", err)
+        }
+    }
+
+    Ok(added)
+}
+
+async fn load_system_store(root_cert_store: &mut RootCertStore) -> Result<usize, SecureLinkError> {
+
+    // Reading the platform store blocks, on Windows and macOS it goes through the OS APIs
+    let native_certs = tokio::task::spawn_blocking(rustls_native_certs::load_native_certs).await
+        .map_err(|err| SecureLinkError::TrustAnchorError(format!("loading the system trust store failed: {}", err)))?;
+
+    for err in &native_certs.errors {
+        warn!("error while loading the system trust store: {}", err);
+    }
+
+    if native_certs.certs.is_empty() {
+        return Err(SecureLinkError::TrustAnchorError("no certificates found in the system trust store".to_string()));
+    }
+
+    let (added, ignored) = root_cert_store.add_parsable_certificates(native_certs.certs);
+
+    if ignored > 0 {
+        warn!("ignored {} invalid certificates in the system trust store", ignored);
+    }
+
+    Ok(added)
+}